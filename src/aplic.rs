@@ -0,0 +1,609 @@
+use crate::device::Device;
+use crate::exception::*;
+use crate::imsic::Imsic;
+use crate::irq::InterruptSink;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Number of interrupt sources. Source 0 does not exist.
+pub const APLIC_NUM_SOURCES: u64 = 63;
+// The valid source numbers.
+const SOURCES: std::ops::RangeInclusive<u64> = 1..=APLIC_NUM_SOURCES;
+
+// Domain registers, relative to the domain base address.
+pub const DOMAINCFG: u64 = 0x0000;
+pub const SOURCECFG: u64 = 0x0004;
+pub const MMSIADDRCFG: u64 = 0x1bc0;
+pub const SMSIADDRCFGH: u64 = 0x1bcc;
+pub const SETIP: u64 = 0x1c00;
+pub const SETIPNUM: u64 = 0x1cdc;
+pub const IN_CLRIP: u64 = 0x1d00;
+pub const CLRIPNUM: u64 = 0x1ddc;
+pub const SETIE: u64 = 0x1e00;
+pub const SETIENUM: u64 = 0x1edc;
+pub const CLRIE: u64 = 0x1f00;
+pub const CLRIENUM: u64 = 0x1fdc;
+pub const SETIPNUM_LE: u64 = 0x2000;
+pub const SETIPNUM_BE: u64 = 0x2004;
+pub const GENMSI: u64 = 0x3000;
+pub const TARGET: u64 = 0x3004;
+pub const IDC: u64 = 0x4000;
+
+// Interrupt delivery control registers, relative to the IDC of a hart.
+pub const IDELIVERY: u64 = 0x00;
+pub const IFORCE: u64 = 0x04;
+pub const ITHRESHOLD: u64 = 0x08;
+pub const TOPI: u64 = 0x18;
+pub const CLAIMI: u64 = 0x1c;
+
+// domaincfg fields. Bits 31:24 always read as 0x80.
+const DOMAINCFG_RO80: u64 = 0x80 << 24;
+const DOMAINCFG_IE: u64 = 1 << 8;
+const DOMAINCFG_DM: u64 = 1 << 2;
+
+// sourcecfg fields.
+const SOURCECFG_D: u64 = 1 << 10;
+const SOURCECFG_SM: u64 = 0b111;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SourceMode {
+    Inactive,
+    Detached,
+    Edge1,
+    Edge0,
+    Level1,
+    Level0,
+}
+
+impl SourceMode {
+    pub fn from_sm(sm: u64) -> Self {
+        match sm {
+            1 => SourceMode::Detached,
+            4 => SourceMode::Edge1,
+            5 => SourceMode::Edge0,
+            6 => SourceMode::Level1,
+            7 => SourceMode::Level0,
+            // Reserved values are treated as inactive.
+            _ => SourceMode::Inactive,
+        }
+    }
+
+    pub fn sm(self) -> u64 {
+        match self {
+            SourceMode::Inactive => 0,
+            SourceMode::Detached => 1,
+            SourceMode::Edge1 => 4,
+            SourceMode::Edge0 => 5,
+            SourceMode::Level1 => 6,
+            SourceMode::Level0 => 7,
+        }
+    }
+
+    fn is_level(self) -> bool {
+        matches!(self, SourceMode::Level1 | SourceMode::Level0)
+    }
+
+    // The rectified input value of a source for a raw input level.
+    fn rectify(self, input: bool) -> bool {
+        match self {
+            SourceMode::Edge1 | SourceMode::Level1 => input,
+            SourceMode::Edge0 | SourceMode::Level0 => !input,
+            _ => false,
+        }
+    }
+}
+
+/// An interrupt domain. The root domain takes M-level interrupts, its single
+/// child domain takes S-level interrupts for sources delegated to it.
+pub struct Domain {
+    is_root: bool,
+    domaincfg: u64,
+    sourcecfg: Vec<u64>,
+    pending: Vec<bool>,
+    enabled: Vec<bool>,
+    target: Vec<u64>,
+    msiaddrcfg: [u64; 4],
+    genmsi: u64,
+    // Interrupt delivery control for hart 0 (direct delivery mode).
+    idelivery: u64,
+    iforce: u64,
+    ithreshold: u64,
+}
+
+impl Domain {
+    pub fn new(is_root: bool) -> Self {
+        let sources = APLIC_NUM_SOURCES as usize + 1;
+        Self {
+            is_root,
+            domaincfg: 0,
+            sourcecfg: vec![0; sources],
+            pending: vec![false; sources],
+            enabled: vec![false; sources],
+            target: vec![0; sources],
+            msiaddrcfg: [0; 4],
+            genmsi: 0,
+            idelivery: 0,
+            iforce: 0,
+            ithreshold: 0,
+        }
+    }
+
+    fn msi_mode(&self) -> bool {
+        self.domaincfg & DOMAINCFG_DM != 0
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.domaincfg & DOMAINCFG_IE != 0
+    }
+
+    fn delegated(&self, source: usize) -> bool {
+        self.sourcecfg[source] & SOURCECFG_D != 0
+    }
+
+    fn mode(&self, source: usize) -> SourceMode {
+        if self.delegated(source) {
+            return SourceMode::Inactive;
+        }
+        SourceMode::from_sm(self.sourcecfg[source] & SOURCECFG_SM)
+    }
+
+    // Interrupt priority of a source in direct delivery mode.
+    fn iprio(&self, source: usize) -> u64 {
+        self.target[source] & 0xff
+    }
+
+    /// The highest priority pending-and-enabled source with its priority
+    /// in the topi format (direct delivery mode), or 0.
+    fn topi(&self) -> u64 {
+        let mut top = 0;
+        let mut top_prio = u64::MAX;
+        for source in 1..=APLIC_NUM_SOURCES as usize {
+            if !(self.pending[source] && self.enabled[source]) {
+                continue;
+            }
+            if self.mode(source) == SourceMode::Inactive {
+                continue;
+            }
+            // Lower priority numbers mean higher priority; ties are broken
+            // in favour of the lower source number.
+            let prio = self.iprio(source);
+            if self.ithreshold != 0 && prio >= self.ithreshold {
+                continue;
+            }
+            if prio < top_prio {
+                top_prio = prio;
+                top = source as u64;
+            }
+        }
+        if top == 0 {
+            return 0;
+        }
+        (top << 16) | top_prio
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        if self.msi_mode() || !self.interrupts_enabled() || self.idelivery == 0 {
+            return false;
+        }
+        self.topi() != 0 || self.iforce != 0
+    }
+}
+
+/// The Advanced Platform-Level Interrupt Controller with an M-level root
+/// domain and an S-level child domain for a single hart.
+pub struct Aplic {
+    pub root: Domain,
+    pub child: Domain,
+    input: Vec<bool>,
+    imsic: Rc<RefCell<Imsic>>,
+}
+
+impl Aplic {
+    pub fn new(imsic: Rc<RefCell<Imsic>>) -> Self {
+        Self {
+            root: Domain::new(true),
+            child: Domain::new(false),
+            input: vec![false; APLIC_NUM_SOURCES as usize + 1],
+            imsic,
+        }
+    }
+
+    fn domain(&mut self, is_root: bool) -> &mut Domain {
+        if is_root {
+            &mut self.root
+        } else {
+            &mut self.child
+        }
+    }
+
+    // The domain in which a source is active.
+    fn owner(&mut self, source: usize) -> &mut Domain {
+        if self.root.delegated(source) {
+            &mut self.child
+        } else {
+            &mut self.root
+        }
+    }
+
+    /// Whether the domain asserts an external interrupt at the hart in
+    /// direct delivery mode. In MSI delivery mode, interrupts reach the hart
+    /// through the IMSIC instead.
+    pub fn interrupt_pending(&self, is_root: bool) -> bool {
+        if is_root {
+            self.root.interrupt_pending()
+        } else {
+            self.child.interrupt_pending()
+        }
+    }
+
+    // Set the pending bit of a source by software (setip, setipnum).
+    fn software_set_pending(&mut self, is_root: bool, source: usize) {
+        let input = self.input[source];
+        let domain = self.domain(is_root);
+        let mode = domain.mode(source);
+        let allowed = match mode {
+            SourceMode::Inactive => false,
+            SourceMode::Detached | SourceMode::Edge1 | SourceMode::Edge0 => true,
+            // In direct delivery mode, the pending bit of a level-sensitive
+            // source always follows the rectified input. In MSI delivery
+            // mode it can only be set while the rectified input is high.
+            SourceMode::Level1 | SourceMode::Level0 => domain.msi_mode() && mode.rectify(input),
+        };
+        if allowed {
+            domain.pending[source] = true;
+        }
+    }
+
+    // Clear the pending bit of a source by software (in_clrip, clripnum).
+    fn software_clear_pending(&mut self, is_root: bool, source: usize) {
+        let domain = self.domain(is_root);
+        if domain.mode(source).is_level() && !domain.msi_mode() {
+            return;
+        }
+        domain.pending[source] = false;
+    }
+
+    // Read claimi: claim the top interrupt of the domain.
+    fn claim(&mut self, is_root: bool) -> u64 {
+        let input = self.input.clone();
+        let domain = self.domain(is_root);
+        let topi = domain.topi();
+        if topi == 0 {
+            domain.iforce = 0;
+            return 0;
+        }
+        let source = (topi >> 16) as usize;
+        let mode = domain.mode(source);
+        // A level-sensitive source stays pending while its input is asserted.
+        domain.pending[source] = mode.is_level() && mode.rectify(input[source]);
+        topi
+    }
+
+    /// Forward pending-and-enabled interrupts as MSIs to the IMSIC of the
+    /// hart. The root domain delivers to the M-level interrupt file, the
+    /// child domain to the S-level one.
+    fn deliver(&mut self) {
+        for is_root in [true, false] {
+            let domain = if is_root {
+                &mut self.root
+            } else {
+                &mut self.child
+            };
+            if !domain.msi_mode() || !domain.interrupts_enabled() {
+                continue;
+            }
+            for source in 1..=APLIC_NUM_SOURCES as usize {
+                if !(domain.pending[source] && domain.enabled[source]) {
+                    continue;
+                }
+                if domain.mode(source) == SourceMode::Inactive {
+                    continue;
+                }
+                domain.pending[source] = false;
+                let eiid = domain.target[source] & 0x7ff;
+                let mut imsic = self.imsic.borrow_mut();
+                let file = if is_root { &mut imsic.m } else { &mut imsic.s };
+                file.set_pending(eiid);
+            }
+        }
+    }
+
+    fn write_sourcecfg(&mut self, is_root: bool, source: usize, value: u64) {
+        let input = self.input[source];
+        if is_root {
+            // Taking a source back from the child domain makes it inactive there.
+            if self.root.delegated(source) && value & SOURCECFG_D == 0 {
+                self.child.sourcecfg[source] = 0;
+                self.child.pending[source] = false;
+                self.child.enabled[source] = false;
+            }
+        } else if !self.root.delegated(source) {
+            // Sources not delegated to the child domain are read-only zero there.
+            return;
+        }
+        let domain = self.domain(is_root);
+        if value & SOURCECFG_D != 0 {
+            // Only the root domain has a child to delegate to, and it has
+            // exactly one (child index 0).
+            if domain.is_root {
+                domain.sourcecfg[source] = SOURCECFG_D;
+                domain.pending[source] = false;
+                domain.enabled[source] = false;
+            }
+            return;
+        }
+        let mode = SourceMode::from_sm(value & SOURCECFG_SM);
+        domain.sourcecfg[source] = mode.sm();
+        match mode {
+            SourceMode::Inactive => {
+                domain.pending[source] = false;
+                domain.enabled[source] = false;
+            }
+            SourceMode::Level1 | SourceMode::Level0 => {
+                domain.pending[source] = mode.rectify(input);
+            }
+            _ => {}
+        }
+    }
+
+    fn write_target(&mut self, is_root: bool, source: usize, value: u64) {
+        let domain = self.domain(is_root);
+        domain.target[source] = if domain.msi_mode() {
+            // Hart index 0 and guest index 0: only the EIID is kept.
+            value & 0x7ff
+        } else {
+            // Priority 0 is not a valid priority and is written as 1.
+            match value & 0xff {
+                0 => 1,
+                iprio => iprio,
+            }
+        };
+    }
+
+    fn send_genmsi(&mut self, is_root: bool, value: u64) {
+        let domain = self.domain(is_root);
+        if !domain.msi_mode() {
+            return;
+        }
+        // The MSI is sent immediately, so the busy bit is never observed.
+        domain.genmsi = value & 0x7ff;
+        let eiid = domain.genmsi;
+        let mut imsic = self.imsic.borrow_mut();
+        let file = if is_root { &mut imsic.m } else { &mut imsic.s };
+        file.set_pending(eiid);
+    }
+
+    fn source_index(offset: u64, base: u64) -> Option<usize> {
+        let source = (offset - base) / 4 + 1;
+        if (offset - base).is_multiple_of(4) && source <= APLIC_NUM_SOURCES {
+            Some(source as usize)
+        } else {
+            None
+        }
+    }
+
+    fn bit_word(bits: &[bool], word: u64) -> u64 {
+        let mut value = 0;
+        for bit in 0..32 {
+            let source = (word * 32 + bit) as usize;
+            if source < bits.len() && bits[source] {
+                value |= 1 << bit;
+            }
+        }
+        value
+    }
+
+    /// Read a register of the root (`is_root`) or child domain.
+    pub fn load(&mut self, is_root: bool, offset: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        if offset == IDC + CLAIMI {
+            return Ok(self.claim(is_root));
+        }
+        let input = self.input.clone();
+        let domain = self.domain(is_root);
+        let value = match offset {
+            DOMAINCFG => domain.domaincfg | DOMAINCFG_RO80,
+            SOURCECFG..MMSIADDRCFG => match Self::source_index(offset, SOURCECFG) {
+                Some(source) => domain.sourcecfg[source],
+                None => 0,
+            },
+            MMSIADDRCFG..=SMSIADDRCFGH if domain.is_root => {
+                domain.msiaddrcfg[((offset - MMSIADDRCFG) / 4) as usize]
+            }
+            SETIP..SETIPNUM => Self::bit_word(&domain.pending, (offset - SETIP) / 4),
+            IN_CLRIP..CLRIPNUM => {
+                // Reads return the rectified input values of active sources.
+                let rectified: Vec<bool> = (0..input.len())
+                    .map(|source| domain.mode(source).rectify(input[source]))
+                    .collect();
+                Self::bit_word(&rectified, (offset - IN_CLRIP) / 4)
+            }
+            SETIE..SETIENUM => Self::bit_word(&domain.enabled, (offset - SETIE) / 4),
+            GENMSI => domain.genmsi,
+            TARGET..IDC => match Self::source_index(offset, TARGET) {
+                Some(source) => domain.target[source],
+                None => 0,
+            },
+            // Only the IDC of hart 0 exists.
+            _ if (IDC..IDC + 0x20).contains(&offset) => match offset - IDC {
+                IDELIVERY => domain.idelivery,
+                IFORCE => domain.iforce,
+                ITHRESHOLD => domain.ithreshold,
+                TOPI => domain.topi(),
+                _ => 0,
+            },
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    /// Write a register of the root (`is_root`) or child domain.
+    pub fn store(
+        &mut self,
+        is_root: bool,
+        offset: u64,
+        size: u64,
+        value: u64,
+    ) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        let value = value & 0xffff_ffff;
+        match offset {
+            // Big-endian mode is not supported: BE is read-only zero.
+            DOMAINCFG => self.domain(is_root).domaincfg = value & (DOMAINCFG_IE | DOMAINCFG_DM),
+            SOURCECFG..MMSIADDRCFG => {
+                if let Some(source) = Self::source_index(offset, SOURCECFG) {
+                    self.write_sourcecfg(is_root, source, value);
+                }
+            }
+            MMSIADDRCFG..=SMSIADDRCFGH => {
+                let domain = self.domain(is_root);
+                if domain.is_root {
+                    domain.msiaddrcfg[((offset - MMSIADDRCFG) / 4) as usize] = value;
+                }
+            }
+            SETIP..SETIPNUM => {
+                let word = (offset - SETIP) / 4;
+                for bit in 0..32 {
+                    let source = word * 32 + bit;
+                    if value >> bit & 1 != 0 && SOURCES.contains(&source) {
+                        self.software_set_pending(is_root, source as usize);
+                    }
+                }
+            }
+            SETIPNUM | SETIPNUM_LE | SETIPNUM_BE => {
+                let source = if offset == SETIPNUM_BE {
+                    (value as u32).swap_bytes() as u64
+                } else {
+                    value
+                };
+                if SOURCES.contains(&source) {
+                    self.software_set_pending(is_root, source as usize);
+                }
+            }
+            IN_CLRIP..CLRIPNUM => {
+                let word = (offset - IN_CLRIP) / 4;
+                for bit in 0..32 {
+                    let source = word * 32 + bit;
+                    if value >> bit & 1 != 0 && SOURCES.contains(&source) {
+                        self.software_clear_pending(is_root, source as usize);
+                    }
+                }
+            }
+            CLRIPNUM if SOURCES.contains(&value) => {
+                self.software_clear_pending(is_root, value as usize);
+            }
+            SETIE..SETIENUM | SETIENUM | CLRIE..CLRIENUM | CLRIENUM => {
+                let enable = offset < CLRIE;
+                let sources: Vec<u64> = match offset {
+                    SETIENUM | CLRIENUM => vec![value],
+                    _ => {
+                        let word = (offset - if enable { SETIE } else { CLRIE }) / 4;
+                        (0..32)
+                            .filter(|bit| value >> bit & 1 != 0)
+                            .map(|bit| word * 32 + bit)
+                            .collect()
+                    }
+                };
+                let domain = self.domain(is_root);
+                for source in sources {
+                    if SOURCES.contains(&source) {
+                        let source = source as usize;
+                        if domain.mode(source) != SourceMode::Inactive {
+                            domain.enabled[source] = enable;
+                        }
+                    }
+                }
+            }
+            GENMSI => self.send_genmsi(is_root, value),
+            TARGET..IDC => {
+                if let Some(source) = Self::source_index(offset, TARGET) {
+                    self.write_target(is_root, source, value);
+                }
+            }
+            _ if (IDC..IDC + 0x20).contains(&offset) => {
+                let domain = self.domain(is_root);
+                match offset - IDC {
+                    IDELIVERY => domain.idelivery = value & 0b1,
+                    IFORCE => domain.iforce = value & 0b1,
+                    ITHRESHOLD => domain.ithreshold = value & 0xff,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        self.deliver();
+        Ok(())
+    }
+}
+
+/// The register window of one interrupt domain, as mapped on the bus.
+pub struct AplicDomainMmio {
+    aplic: Rc<RefCell<Aplic>>,
+    is_root: bool,
+}
+
+impl AplicDomainMmio {
+    pub fn new(aplic: Rc<RefCell<Aplic>>, is_root: bool) -> Self {
+        Self { aplic, is_root }
+    }
+}
+
+impl Device for AplicDomainMmio {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        self.aplic.borrow_mut().load(self.is_root, offset, size)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        self.aplic
+            .borrow_mut()
+            .store(self.is_root, offset, size, value)
+    }
+
+    fn reset(&mut self) {
+        let mut aplic = self.aplic.borrow_mut();
+        *aplic.domain(self.is_root) = Domain::new(self.is_root);
+    }
+}
+
+impl InterruptSink for Aplic {
+    fn set_level(&mut self, irq: u64, level: bool) {
+        if irq == 0 || irq > APLIC_NUM_SOURCES {
+            return;
+        }
+        let source = irq as usize;
+        let old = self.input[source];
+        self.input[source] = level;
+        let domain = self.owner(source);
+        let mode = domain.mode(source);
+        let (was, now) = (mode.rectify(old), mode.rectify(level));
+        match mode {
+            // Edge-sensitive sources become pending on a rising edge of the
+            // rectified input.
+            SourceMode::Edge1 | SourceMode::Edge0 if now && !was => {
+                domain.pending[source] = true;
+            }
+            SourceMode::Level1 | SourceMode::Level0 => {
+                if domain.msi_mode() {
+                    if now && !was {
+                        domain.pending[source] = true;
+                    } else if !now {
+                        domain.pending[source] = false;
+                    }
+                } else {
+                    domain.pending[source] = now;
+                }
+            }
+            _ => {}
+        }
+        self.deliver();
+    }
+
+    fn pulse(&mut self, irq: u64) {
+        self.set_level(irq, true);
+        self.set_level(irq, false);
+    }
+}
@@ -1,58 +1,390 @@
-use crate::clint::Clint;
+use crate::aplic::*;
+use crate::clint::*;
+use crate::device::*;
 use crate::dram::*;
 use crate::exception::*;
+use crate::file_memory::*;
+use crate::htif::*;
+use crate::imsic::*;
+use crate::irq::*;
 use crate::lib::address::*;
-use crate::plic::Plic;
+use crate::machine::*;
+use crate::plic::*;
+use crate::pmp::Access;
+use crate::power::*;
+use crate::rom::*;
+use crate::scheduler::Scheduler;
+use crate::sifive_test::*;
+use crate::sifive_uart::*;
 use crate::uart::*;
+use crate::virtio::*;
 use std::cell::RefCell;
+use std::fs;
 use std::rc::Rc;
 
 pub struct Bus {
-    pub dram: Dram,
-    pub plic: Rc<RefCell<Plic>>,
-    pub uart: UART,
+    // Mapped regions, sorted by base address and never overlapping.
+    regions: Vec<Region>,
+    // Index of the region hit by the last access.
+    last: usize,
+    pub plic: Option<Rc<RefCell<Plic>>>,
+    pub aplic: Option<Rc<RefCell<Aplic>>>,
+    pub imsic: Option<Rc<RefCell<Imsic>>>,
     pub clint: Clint,
+    pub scheduler: Scheduler,
+    /// Power-off and reset requests made by the guest.
+    pub power: PowerControl,
 }
 
 impl Bus {
-    pub fn new(timer_freq: u64, binary: Vec<u8>) -> Self {
-        let plic = Rc::new(RefCell::new(Plic::new()));
-        Self {
-            dram: Dram::new(binary),
-            uart: UART::new(Rc::clone(&plic)),
-            plic,
-            clint: Clint::new(timer_freq),
+    pub fn new(config: &MachineConfig, binary: Vec<u8>) -> Self {
+        let has_sswi = config
+            .devices
+            .iter()
+            .any(|device| device.kind == DeviceKind::AclintSswi);
+        let mut bus = Self {
+            regions: Vec::new(),
+            last: 0,
+            plic: None,
+            aplic: None,
+            imsic: None,
+            clint: Clint::new(config.timer_freq, config.clock, has_sswi),
+            scheduler: Scheduler::new(),
+            power: PowerControl::new(),
+        };
+        // The binary is loaded at the start of the first memory region and
+        // the device tree at its end, aligned down to a page.
+        let mut binary = Some(binary);
+        let dtb = config.dtb.as_ref().map(|path| {
+            fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e))
+        });
+        let first = &config.memory[0];
+        let dtb_offset = dtb.as_ref().map(|dtb| {
+            first
+                .size
+                .checked_sub(dtb.len() as u64)
+                .expect("The device tree does not fit in memory")
+                & !0xfff
+        });
+        let mut dtb = dtb.zip(dtb_offset);
+        for memory in &config.memory {
+            let ram: Rc<RefCell<dyn Device>> = match &memory.file {
+                Some(path) => Rc::new(RefCell::new(
+                    FileMemory::open(path, memory.size, memory.mapping)
+                        .unwrap_or_else(|e| panic!("Failed to map {}: {}", path, e)),
+                )),
+                None => {
+                    let mut dram = Dram::new(memory.size, binary.take().unwrap_or_default());
+                    if let Some((dtb, offset)) = dtb.take() {
+                        dram.load_image(offset, dtb, "The device tree");
+                    }
+                    Rc::new(RefCell::new(dram))
+                }
+            };
+            bus.map(
+                &format!("memory@{:x}", memory.base),
+                memory.base,
+                memory.size,
+                ram,
+            )
+            .unwrap_or_else(|e| panic!("Failed to map memory: {}", e));
         }
+
+        // Devices are wired to whichever interrupt controller the machine uses.
+        let sink: Rc<RefCell<dyn InterruptSink>> = match config.irqchip() {
+            IrqChip::Plic => {
+                let chip = Rc::new(RefCell::new(Plic::new()));
+                bus.plic = Some(chip.clone());
+                chip
+            }
+            IrqChip::Aia => {
+                let files = Rc::new(RefCell::new(Imsic::new()));
+                let chip = Rc::new(RefCell::new(Aplic::new(files.clone())));
+                bus.imsic = Some(files);
+                bus.aplic = Some(chip.clone());
+                chip
+            }
+        };
+
+        for device in &config.devices {
+            let (base, irq) = (device.base, device.irq);
+            let name = |base: u64| format!("{}@{:x}", device.kind.name(), base);
+            let region = |base, size, device| Region::new(name(base), base, size, device);
+            let regions: Vec<Region> = match device.kind {
+                DeviceKind::Uart | DeviceKind::SifiveUart => {
+                    let irq = irq.unwrap_or(UART_IRQ);
+                    if let Some(plic) = &bus.plic {
+                        plic.borrow_mut().add_irq(irq, device.trigger);
+                    }
+                    let line = IrqLine::new(sink.clone(), irq);
+                    let uart: Rc<RefCell<dyn Device>> = match device.kind {
+                        DeviceKind::Uart => Rc::new(RefCell::new(UART::new(line))),
+                        _ => Rc::new(RefCell::new(SifiveUart::new(line))),
+                    };
+                    vec![region(base, device.kind.size(), uart)]
+                }
+                DeviceKind::Rom => {
+                    let dtb = dtb_offset.map_or(0, |offset| first.base + offset);
+                    let rom = Rom::new(first.base, dtb);
+                    vec![region(base, device.kind.size(), Rc::new(RefCell::new(rom)))]
+                }
+                DeviceKind::SifiveTest => {
+                    let test = SifiveTest::new(bus.power.clone());
+                    vec![region(
+                        base,
+                        device.kind.size(),
+                        Rc::new(RefCell::new(test)),
+                    )]
+                }
+                DeviceKind::VirtioMmio => {
+                    let slot = VirtioMmioSlot::new();
+                    vec![region(
+                        base,
+                        device.kind.size(),
+                        Rc::new(RefCell::new(slot)),
+                    )]
+                }
+                DeviceKind::Htif => {
+                    let htif = Htif::new(bus.power.clone());
+                    vec![region(
+                        base,
+                        device.kind.size(),
+                        Rc::new(RefCell::new(htif)),
+                    )]
+                }
+                DeviceKind::Plic => match &bus.plic {
+                    Some(plic) => vec![region(base, device.kind.size(), plic.clone())],
+                    None => vec![],
+                },
+                DeviceKind::AplicM | DeviceKind::AplicS => match &bus.aplic {
+                    Some(aplic) => {
+                        let is_root = device.kind == DeviceKind::AplicM;
+                        let window = AplicDomainMmio::new(aplic.clone(), is_root);
+                        vec![region(
+                            base,
+                            device.kind.size(),
+                            Rc::new(RefCell::new(window)),
+                        )]
+                    }
+                    None => vec![],
+                },
+                DeviceKind::ImsicM | DeviceKind::ImsicS => match &bus.imsic {
+                    Some(imsic) => {
+                        let machine = device.kind == DeviceKind::ImsicM;
+                        let page = ImsicFileMmio::new(imsic.clone(), machine);
+                        vec![region(
+                            base,
+                            device.kind.size(),
+                            Rc::new(RefCell::new(page)),
+                        )]
+                    }
+                    None => vec![],
+                },
+                // The legacy CLINT places the MSWI and MTIMER back to back.
+                DeviceKind::Clint => vec![
+                    region(base, ACLINT_MSWI_SIZE, bus.clint.mswi.clone()),
+                    region(
+                        base + ACLINT_MSWI_SIZE,
+                        ACLINT_MTIMER_SIZE,
+                        bus.clint.mtimer.clone(),
+                    ),
+                ],
+                DeviceKind::AclintMswi => {
+                    vec![region(base, ACLINT_MSWI_SIZE, bus.clint.mswi.clone())]
+                }
+                DeviceKind::AclintMtimer => {
+                    vec![region(base, ACLINT_MTIMER_SIZE, bus.clint.mtimer.clone())]
+                }
+                DeviceKind::AclintSswi => match &bus.clint.sswi {
+                    Some(sswi) => vec![region(base, ACLINT_SSWI_SIZE, sswi.clone())],
+                    None => vec![],
+                },
+            };
+            for region in regions {
+                bus.map_region(region)
+                    .unwrap_or_else(|e| panic!("Failed to map a device: {}", e));
+            }
+        }
+        bus
     }
 
-    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if (CLINT_BASE <= addr) && (addr < CLINT_BASE + CLINT_SIZE) {
-            return self.clint.load(addr);
+    /// Map a device at `base` with the attributes the device reports.
+    pub fn map(
+        &mut self,
+        name: &str,
+        base: u64,
+        size: u64,
+        device: Rc<RefCell<dyn Device>>,
+    ) -> Result<(), MapError> {
+        self.map_region(Region::new(name.to_string(), base, size, device))
+    }
+
+    /// Add a region to the address map. Regions may be added at any time
+    /// but must not overlap a region that is already mapped.
+    pub fn map_region(&mut self, region: Region) -> Result<(), MapError> {
+        let (base, size) = (region.base, region.size);
+        if size == 0 || base.checked_add(size).is_none() {
+            return Err(MapError::Invalid { name: region.name });
+        }
+        let index = self.regions.partition_point(|region| region.base < base);
+        let overlaps = |other: &Region| other.base < base + size && base < other.end();
+        let neighbours = [index.checked_sub(1), Some(index)];
+        for neighbour in neighbours.iter().flatten() {
+            if let Some(other) = self.regions.get(*neighbour) {
+                if overlaps(other) {
+                    return Err(MapError::Overlap {
+                        name: region.name,
+                        existing: other.name.clone(),
+                    });
+                }
+            }
+        }
+        self.regions.insert(index, region);
+        self.last = 0;
+        Ok(())
+    }
+
+    // Index of the region containing `addr`.
+    fn find(&mut self, addr: u64) -> Option<usize> {
+        if let Some(region) = self.regions.get(self.last) {
+            if region.contains(addr) {
+                return Some(self.last);
+            }
         }
-        if (PLIC_BASE <= addr) && (addr < PLIC_BASE + PLIC_SIZE) {
-            return self.plic.borrow_mut().load(addr, size);
+        let index = self
+            .regions
+            .partition_point(|region| region.base <= addr)
+            .checked_sub(1)?;
+        if self.regions[index].contains(addr) {
+            self.last = index;
+            Some(index)
+        } else {
+            None
         }
-        if (UART_BASE <= addr) && (addr < UART_BASE + UART_SIZE) {
-            return self.uart.load(addr, size);
+    }
+
+    // Index of the region that holds an access of `size` bits at `addr`,
+    // if the region supports the access width.
+    fn find_access(&mut self, addr: u64, size: u64) -> Option<usize> {
+        let index = self.find(addr)?;
+        let region = &self.regions[index];
+        let fits = addr - region.base + size / 8 <= region.size;
+        if fits && region.pma.allows_width(size) {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Whether an access of `size` bits at `addr` runs past the end of the
+    /// region it starts in.
+    pub fn crosses_region(&mut self, addr: u64, size: u64) -> bool {
+        match self.find(addr) {
+            Some(index) => {
+                let region = &self.regions[index];
+                addr - region.base + size / 8 > region.size
+            }
+            None => false,
         }
-        if (DRAM_BASE <= addr) && (addr < DRAM_BASE + DRAM_SIZE) {
-            return self.dram.load(addr, size);
+    }
+
+    fn read(&mut self, index: usize, addr: u64, size: u64) -> Result<u64, ()> {
+        let region = &self.regions[index];
+        if !region.pma.idempotent {
+            // Device accesses may change the interrupt state.
+            self.scheduler.mark_dirty();
         }
-        Err(Exception::LoadAccessFault(addr))
+        region
+            .device
+            .borrow_mut()
+            .read(addr - region.base, size)
+            .map_err(|_| ())
+    }
+
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let fault = Exception::LoadAccessFault(addr);
+        let index = self.find_access(addr, size).ok_or(fault)?;
+        self.read(index, addr, size).map_err(|_| fault)
     }
+
+    /// Fetch an instruction. Only executable regions can be fetched from.
+    pub fn fetch(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let fault = Exception::InstructionAccessFault(addr);
+        let index = self.find_access(addr, size).ok_or(fault)?;
+        if !self.regions[index].pma.executable {
+            return Err(fault);
+        }
+        self.read(index, addr, size).map_err(|_| fault)
+    }
+
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if (CLINT_BASE <= addr) && (addr < CLINT_BASE + CLINT_SIZE) {
-            return self.clint.store(addr, value);
+        let fault = Exception::StoreAMOAccessFault(addr);
+        let index = self.find_access(addr, size).ok_or(fault)?;
+        let region = &self.regions[index];
+        if !region.pma.idempotent {
+            // Device accesses may change the interrupt state.
+            self.scheduler.mark_dirty();
         }
-        if (PLIC_BASE <= addr) && (addr < PLIC_BASE + PLIC_SIZE) {
-            return self.plic.borrow_mut().store(addr, size, value);
+        region
+            .device
+            .borrow_mut()
+            .write(addr - region.base, size, value)
+            .map_err(|_| fault)
+    }
+
+    /// Check that the region at `addr` supports AMOs of class `class`.
+    pub fn check_amo(&mut self, addr: u64, size: u64, class: AmoSupport) -> Result<(), Exception> {
+        match self.find_access(addr, size) {
+            Some(index) if self.regions[index].pma.amo >= class => Ok(()),
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
-        if (UART_BASE <= addr) && (addr < UART_BASE + UART_SIZE) {
-            return self.uart.store(addr, value);
+    }
+
+    /// Check that the region at `addr` supports LR/SC. A failing LR raises
+    /// a load access fault, a failing SC a store/AMO access fault.
+    pub fn check_lrsc(&mut self, addr: u64, size: u64, store: bool) -> Result<(), Exception> {
+        match self.find_access(addr, size) {
+            Some(index) if self.regions[index].pma.lrsc => Ok(()),
+            _ if store => Err(Exception::StoreAMOAccessFault(addr)),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
-        if (DRAM_BASE <= addr) && (addr < DRAM_BASE + DRAM_SIZE) {
-            return self.dram.store(addr, size, value);
+    }
+
+    /// Host memory backing the RAM page at `addr` for `access`. Pages that
+    /// cannot be fetched from are not returned for fetches.
+    pub fn host_page(&mut self, addr: u64, access: Access) -> Option<HostPage> {
+        let index = self.find(addr)?;
+        let region = &self.regions[index];
+        if access == Access::Fetch && !region.pma.executable {
+            return None;
+        }
+        let mut page = region
+            .device
+            .borrow_mut()
+            .host_page(addr - region.base, access == Access::Store)?;
+        page.base += region.base;
+        Some(page)
+    }
+
+    /// Return every device to its power-on state.
+    pub fn reset(&mut self) {
+        for region in &self.regions {
+            region.device.borrow_mut().reset();
+        }
+        self.scheduler.mark_dirty();
+    }
+
+    /// Advance every device to mtime `now`.
+    pub fn tick(&mut self, now: u64) {
+        for region in &self.regions {
+            region.device.borrow_mut().tick(now);
+        }
+    }
+
+    /// Let every device update its interrupt lines.
+    pub fn check_interrupts(&mut self) {
+        for region in &self.regions {
+            region.device.borrow_mut().check_interrupt();
         }
-        Err(Exception::StoreAMOAccessFault(addr))
     }
 }
@@ -1,37 +1,151 @@
-use crate::csr::Csr;
+use crate::device::{Device, Pma, WIDTH_32, WIDTH_64};
 use crate::lib::address::*;
 use crate::Exception;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::vec;
 
-pub const MSIP_BASE: u64 = CLINT_BASE;
-pub const MTIMECMP_BASE: u64 = CLINT_BASE + 0x4000;
-pub const MTIME: u64 = CLINT_BASE + 0xbff8;
+// Register offsets inside the MSWI device.
+pub const MSIP: u64 = 0x0000;
+// Register offsets inside the MTIMER device.
+pub const MTIMECMP: u64 = 0x0000;
+pub const MTIME: u64 = 0x7ff8;
 pub const MTIME_END: u64 = MTIME + 0x8;
+// Register offsets inside the SSWI device.
+pub const SETSSIP: u64 = 0x0000;
 
-pub const MAX_MSIP: usize = 4096;
+// Instructions executed between two interrupt checks in wall-clock mode,
+// where mtime cannot be predicted from the instruction count.
+pub const WALL_CLOCK_POLL_INSTRUCTIONS: u64 = 1024;
+
+pub const MAX_MSIP: usize = 4095;
 pub const MAX_MTIMECMP: usize = 4095;
 
+/// Placement of the timer and software interrupt devices.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClintLayout {
+    /// Legacy SiFive CLINT: msip, mtimecmp and mtime in one region at CLINT_BASE.
+    Sifive,
+    /// RISC-V ACLINT: separately placed MSWI, MTIMER and SSWI devices.
+    Aclint,
+}
+
+impl ClintLayout {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sifive" => Some(ClintLayout::Sifive),
+            "aclint" => Some(ClintLayout::Aclint),
+            _ => None,
+        }
+    }
+}
+
+/// Source of the mtime counter.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockMode {
+    /// mtime follows the host wall clock at the timer frequency.
+    WallClock,
+    /// mtime advances by a fixed number of ticks per retired instruction,
+    /// so runs are reproducible regardless of host load.
+    Virtual { ticks_per_instruction: u64 },
+}
+
 pub struct Timer {
     begin: u64,
     freq: u64,
+    mode: ClockMode,
+    // Ticks elapsed in virtual clock mode.
+    ticks: u64,
 }
 
-pub struct Clint {
-    msip: Vec<u32>,
+/// Machine-level timer device: per-hart mtimecmp and the shared mtime.
+pub struct Mtimer {
     mtimecmp: Vec<u64>,
     mtime: Timer,
 }
 
+/// Machine-level software interrupt device: per-hart msip.
+pub struct Mswi {
+    msip: Vec<u32>,
+}
+
+/// Supervisor-level software interrupt device. Writing 1 to the setssip
+/// register of a hart sets its SSIP bit; the register always reads as zero.
+pub struct Sswi {
+    ssip: Vec<bool>,
+}
+
+/// The timer and software interrupt devices of the machine. Each device is
+/// mapped on the bus on its own; the CPU reads its interrupt state here.
+pub struct Clint {
+    pub mswi: Rc<RefCell<Mswi>>,
+    pub mtimer: Rc<RefCell<Mtimer>>,
+    pub sswi: Option<Rc<RefCell<Sswi>>>,
+}
+
 impl Timer {
-    pub fn new(freq: u64) -> Self {
+    pub fn new(freq: u64, mode: ClockMode) -> Self {
         assert!(freq > 0, "Frequency must be greater than zero");
-        let mut timer = Self { begin: 0, freq };
+        let mut timer = Self {
+            begin: 0,
+            freq,
+            mode,
+            ticks: 0,
+        };
         timer.rebase(0);
         timer
     }
 
+    /// Account for `count` retired instructions.
+    pub fn retire(&mut self, count: u64) {
+        if let ClockMode::Virtual {
+            ticks_per_instruction,
+        } = self.mode
+        {
+            self.ticks = self.ticks.wrapping_add(count * ticks_per_instruction);
+        }
+    }
+
+    /// Number of instructions to retire until mtime reaches `deadline`.
+    pub fn instructions_until(&self, deadline: u64) -> u64 {
+        match self.mode {
+            ClockMode::Virtual {
+                ticks_per_instruction,
+            } => {
+                let ticks = deadline.saturating_sub(self.get());
+                ticks.div_ceil(ticks_per_instruction.max(1)).max(1)
+            }
+            ClockMode::WallClock => WALL_CLOCK_POLL_INSTRUCTIONS,
+        }
+    }
+
+    /// Let an idle hart wait for mtime to reach `deadline`. Virtual time
+    /// jumps to the deadline; wall-clock time returns how long the host
+    /// has to sleep.
+    pub fn wait_until(&mut self, deadline: u64) -> Duration {
+        let now = self.get();
+        if deadline <= now {
+            return Duration::ZERO;
+        }
+        match self.mode {
+            ClockMode::Virtual { .. } => {
+                self.ticks = self.ticks.wrapping_add(deadline - now);
+                Duration::ZERO
+            }
+            ClockMode::WallClock => {
+                let ticks = deadline - now;
+                Duration::from_secs(ticks / self.freq)
+                    + Duration::from_nanos((ticks % self.freq) * 1_000_000_000 / self.freq)
+            }
+        }
+    }
+
     pub fn current_time(&self) -> u64 {
+        if let ClockMode::Virtual { .. } = self.mode {
+            return self.ticks;
+        }
+
         fn mult_frac(x: u64, n: u64, d: u64) -> u64 {
             // x = qd + r
             let q = x / d;
@@ -50,91 +164,205 @@ impl Timer {
     }
 
     pub fn get(&self) -> u64 {
-        self.current_time() - self.begin
+        self.current_time().wrapping_sub(self.begin)
     }
 
     pub fn rebase(&mut self, time: u64) {
-        self.begin = self.current_time() - time;
+        self.begin = self.current_time().wrapping_sub(time);
+    }
+}
+
+// Read a 64-bit register with a 32-bit access to either half or a 64-bit
+// access to the whole register.
+fn read_reg64(reg: u64, offset: u64, size: u64) -> Option<u64> {
+    match (size, offset & 0b111) {
+        (64, 0) => Some(reg),
+        (32, 0) => Some(reg & 0xffff_ffff),
+        (32, 4) => Some(reg >> 32),
+        _ => None,
+    }
+}
+
+// Returns the new value of a 64-bit register after a write to it.
+fn write_reg64(reg: u64, offset: u64, size: u64, value: u64) -> Option<u64> {
+    match (size, offset & 0b111) {
+        (64, 0) => Some(value),
+        (32, 0) => Some((reg & !0xffff_ffff) | (value & 0xffff_ffff)),
+        (32, 4) => Some((reg & 0xffff_ffff) | (value << 32)),
+        _ => None,
+    }
+}
+
+impl Mtimer {
+    pub fn new(timer_freq: u64, clock: ClockMode) -> Self {
+        Self {
+            mtimecmp: vec![0u64; MAX_MTIMECMP],
+            mtime: Timer::new(timer_freq, clock),
+        }
+    }
+}
+
+impl Device for Mtimer {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        let reg = match offset {
+            MTIMECMP..MTIME => self.mtimecmp[(offset >> 3) as usize],
+            MTIME..MTIME_END => self.mtime.get(),
+            _ => return Err(Exception::LoadAccessFault(offset)),
+        };
+        read_reg64(reg, offset, size).ok_or(Exception::LoadAccessFault(offset))
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        match offset {
+            MTIMECMP..MTIME => {
+                let hart_id = (offset >> 3) as usize;
+                self.mtimecmp[hart_id] = write_reg64(self.mtimecmp[hart_id], offset, size, value)
+                    .ok_or(Exception::StoreAMOAccessFault(offset))?;
+            }
+            MTIME..MTIME_END => {
+                let time = write_reg64(self.mtime.get(), offset, size, value)
+                    .ok_or(Exception::StoreAMOAccessFault(offset))?;
+                self.mtime.rebase(time);
+            }
+            _ => return Err(Exception::StoreAMOAccessFault(offset)),
+        }
+        Ok(())
+    }
+
+    fn pma(&self) -> Pma {
+        Pma::io(WIDTH_32 | WIDTH_64)
+    }
+
+    fn reset(&mut self) {
+        self.mtimecmp.iter_mut().for_each(|cmp| *cmp = 0);
+        self.mtime.rebase(0);
+    }
+}
+
+impl Mswi {
+    pub fn new() -> Self {
+        Self {
+            msip: vec![0u32; MAX_MSIP],
+        }
+    }
+}
+
+impl Device for Mswi {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 || !offset.is_multiple_of(4) {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        let hart_id = ((offset - MSIP) >> 2) as usize;
+        Ok(self.msip.get(hart_id).copied().unwrap_or(0) as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 || !offset.is_multiple_of(4) {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        let hart_id = ((offset - MSIP) >> 2) as usize;
+        if let Some(msip) = self.msip.get_mut(hart_id) {
+            // Only bit 0 of msip is writable.
+            *msip = value as u32 & 0b1;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.msip.iter_mut().for_each(|msip| *msip = 0);
+    }
+}
+
+impl Sswi {
+    pub fn new() -> Self {
+        Self {
+            ssip: vec![false; MAX_MSIP],
+        }
+    }
+}
+
+impl Device for Sswi {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 || !offset.is_multiple_of(4) {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        Ok(0)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 || !offset.is_multiple_of(4) {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        let hart_id = ((offset - SETSSIP) >> 2) as usize;
+        if let Some(ssip) = self.ssip.get_mut(hart_id) {
+            if value & 0b1 != 0 {
+                *ssip = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.ssip.iter_mut().for_each(|ssip| *ssip = false);
     }
 }
 
 impl Clint {
-    pub fn new(timer_freq: u64) -> Self {
+    pub fn new(timer_freq: u64, clock: ClockMode, sswi: bool) -> Self {
         Clint {
-            msip: vec![0u32; MAX_MSIP],
-            mtimecmp: vec![0u64; MAX_MTIMECMP],
-            mtime: Timer::new(timer_freq),
+            mswi: Rc::new(RefCell::new(Mswi::new())),
+            mtimer: Rc::new(RefCell::new(Mtimer::new(timer_freq, clock))),
+            sswi: if sswi {
+                Some(Rc::new(RefCell::new(Sswi::new())))
+            } else {
+                None
+            },
         }
     }
 
+    /// Current value of mtime, also read through the time CSR.
+    pub fn time(&self) -> u64 {
+        self.mtimer.borrow().mtime.get()
+    }
+
+    pub fn mtimecmp(&self, hart_id: u64) -> u64 {
+        self.mtimer.borrow().mtimecmp[hart_id as usize]
+    }
+
+    /// Number of instructions to retire until mtime reaches `deadline`.
+    pub fn instructions_until(&self, deadline: u64) -> u64 {
+        self.mtimer.borrow().mtime.instructions_until(deadline)
+    }
+
+    /// See `Timer::wait_until`.
+    pub fn wait_until(&mut self, deadline: u64) -> Duration {
+        self.mtimer.borrow_mut().mtime.wait_until(deadline)
+    }
+
+    /// Advance virtual time after an instruction retires.
+    pub fn retire(&mut self) {
+        self.mtimer.borrow_mut().mtime.retire(1);
+    }
+
     pub fn check_interrupts(&mut self, hart_id: u64) -> (bool, bool) {
-        let time_elapse = self.mtimecmp[hart_id as usize].saturating_sub(self.mtime.get());
+        let time_elapse = self.mtimecmp(hart_id).saturating_sub(self.time());
         let (mut xtip, mut xsip) = (false, false);
         // xTIP
         if time_elapse == 0 {
             xtip = true;
         }
         // xSIP
-        if self.msip[hart_id as usize] > 0 {
+        if self.mswi.borrow().msip[hart_id as usize] > 0 {
             xsip = true;
         }
         return (xtip, xsip);
     }
 
-    pub fn load(&self, addr: u64) -> Result<u64, Exception> {
-        match addr {
-            MSIP_BASE..MTIMECMP_BASE => {
-                let hart_id = ((addr - MSIP_BASE) >> 2) as usize;
-                Ok(self.msip[hart_id] as u64)
-            }
-            MTIMECMP_BASE..MTIME => {
-                let hart_id = ((addr - MSIP_BASE) >> 3) as usize;
-                Ok(self.mtimecmp[hart_id])
-            }
-            MTIME..MTIME_END => {
-                let time = self.mtime.get();
-                // 0xbff8 (low) or 0xbffc (high)
-                let value = (time >> (32 & -((((addr - CLINT_BASE) & 0b100) > 0) as i64) as u64))
-                    as u32 as u64;
-                Ok(value)
-            }
-            _ => Err(Exception::LoadAccessFault(addr)),
-        }
-    }
-
-    pub fn store(&mut self, addr: u64, value: u64) -> Result<(), Exception> {
-        match addr {
-            MSIP_BASE..MTIMECMP_BASE => {
-                let hart_id = ((addr - MSIP_BASE) >> 2) as usize;
-                self.msip[hart_id] = value as u32;
-                Ok(())
-            }
-            MTIMECMP_BASE..MTIME => {
-                let addr = addr - MTIMECMP_BASE;
-                let hart_id = (addr >> 3) as usize;
-                let mut upper = self.mtimecmp[hart_id] >> 32;
-                let mut lower = self.mtimecmp[hart_id] & 0xffff_ffff;
-                if (addr & 0b100) > 0 {
-                    upper = value;
-                } else {
-                    lower = value;
-                }
-                self.mtimecmp[hart_id] = (upper << 32) | lower;
-                Ok(())
-            }
-            MTIME..MTIME_END => {
-                let addr = addr - MTIMECMP_BASE;
-                let mut upper = self.mtime.begin >> 32;
-                let mut lower = self.mtime.begin & 0xffff_ffff;
-                if (addr & 0b100) > 0 {
-                    upper = value;
-                } else {
-                    lower = value;
-                }
-                self.mtime.rebase((upper << 32) | lower);
-                Ok(())
-            }
-            _ => Err(Exception::StoreAMOAccessFault(addr)),
+    /// Returns true once for every setssip write to the hart since the last call.
+    pub fn take_ssip(&mut self, hart_id: u64) -> bool {
+        match &self.sswi {
+            Some(sswi) => std::mem::replace(&mut sswi.borrow_mut().ssip[hart_id as usize], false),
+            None => false,
         }
     }
 }
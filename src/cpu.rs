@@ -1,8 +1,13 @@
 use crate::bus::*;
 use crate::csr::*;
+use crate::device::AmoSupport;
 use crate::exception::*;
+use crate::hpm::*;
 use crate::interrupt::*;
 use crate::lib::address::*;
+use crate::lib::cpu_page_cache::CachedPage;
+use crate::machine::*;
+use crate::pmp::*;
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -29,40 +34,136 @@ pub struct Cpu {
     pub mode: Mode,
     pub csr: Csr,
     pub bus: Bus,
+    pub pmp: Pmp,
+    pub hpm: Hpm,
+    pub misaligned: MisalignedPolicy,
+    pub halt: HaltPolicy,
+    /// The RAM pages last fetched from, loaded from and stored to.
+    pub page_cache: [Option<CachedPage>; 3],
+    /// The hart is stalled in WFI until an interrupt becomes pending.
+    pub wfi: bool,
+    /// Instructions retired since the emulator started, across resets.
+    pub retired: u64,
+    reset_vector: u64,
+    misa: u64,
 }
 
 impl Cpu {
-    pub fn new(timer_freq: u64, binary: Vec<u8>) -> Self {
-        let mut regs = [0; 32];
-        regs[2] = (DRAM_BASE + DRAM_SIZE) as u64;
-        Self {
-            regs,
-            pc: DRAM_BASE,
+    pub fn new(config: &MachineConfig, binary: Vec<u8>) -> Self {
+        let mut cpu = Self {
+            regs: [0; 32],
+            pc: 0,
             mode: Mode::Machine,
-            bus: Bus::new(timer_freq, binary),
+            bus: Bus::new(config, binary),
             csr: Csr::new(),
+            pmp: Pmp::new(),
+            hpm: Hpm::new(),
+            misaligned: config.misaligned,
+            halt: config.halt,
+            page_cache: [None; 3],
+            wfi: false,
+            retired: 0,
+            reset_vector: config.reset_vector(),
+            misa: config.misa().unwrap_or(0),
+        };
+        cpu.reset();
+        cpu
+    }
+
+    /// Reset the machine: every device returns to its power-on state and
+    /// the hart starts over at the reset vector in M-mode.
+    ///
+    /// The general-purpose registers are cleared, mstatus.MIE and
+    /// mstatus.MPRV are 0, misa reports the configured ISA, mcause is 0 (no
+    /// reset cause is distinguished) and the PMP entries are off.
+    pub fn reset(&mut self) {
+        self.bus.reset();
+        self.regs = [0; 32];
+        self.pc = self.reset_vector;
+        self.mode = Mode::Machine;
+        self.wfi = false;
+        self.csr = Csr::new();
+        self.csr.set_misa(self.misa);
+        self.csr.store(MHARTID, 0);
+        let mut mstatus = 0;
+        if self.misa & (1 << ('u' as u64 - 'a' as u64)) != 0 {
+            mstatus |= MSTATUS_UXL_64;
+        }
+        if self.misa & (1 << ('s' as u64 - 'a' as u64)) != 0 {
+            mstatus |= MSTATUS_SXL_64;
         }
+        self.csr.store(MSTATUS, mstatus);
+        self.pmp = Pmp::new();
+        self.hpm = Hpm::new();
+        self.flush_page_cache();
     }
+
     /// Load a value from a dram.
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if !addr.is_multiple_of(size / 8) {
+            return self.load_misaligned(addr, size);
+        }
+        if let Some(value) = self.cached_read(addr, size, Access::Load) {
+            return Ok(value);
+        }
+        self.pmp_check(addr, size, Access::Load)?;
         self.bus.load(addr, size)
     }
 
     /// Store a value to a dram.
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if !addr.is_multiple_of(size / 8) {
+            return self.store_misaligned(addr, size, value);
+        }
+        if self.cached_write(addr, size, value) {
+            return Ok(());
+        }
+        self.pmp_check(addr, size, Access::Store)?;
         self.bus.store(addr, size, value)
     }
 
     pub fn csr_load(&self, addr: u64) -> u64 {
+        // The time CSR is a read-only shadow of the memory-mapped mtime.
+        if addr == TIME {
+            return self.bus.clint.time();
+        }
+        if let Some(value) = self.aia_csr_load(addr) {
+            return value;
+        }
+        if let Some(value) = self.pmp.csr_load(addr) {
+            return value;
+        }
+        if let Some(value) = self.hpm.csr_load(addr) {
+            // Below M-mode, scountovf only shows the counters enabled in
+            // mcounteren.
+            if addr == SCOUNTOVF && self.mode != Mode::Machine {
+                return value & self.csr.load(MCOUNTEREN);
+            }
+            return value;
+        }
         self.csr.load(addr)
     }
 
     pub fn csr_store(&mut self, addr: u64, value: u64) {
+        if self.aia_csr_store(addr, value) {
+            return;
+        }
+        if self.pmp.csr_store(addr, value) {
+            self.flush_page_cache();
+            return;
+        }
+        if self.hpm.csr_store(addr, value) {
+            return;
+        }
         self.csr.store(addr, value);
     }
 
     pub fn fetch(&mut self) -> Result<u64, Exception> {
-        match self.bus.load(self.pc, 32) {
+        if let Some(inst) = self.cached_read(self.pc, 32, Access::Fetch) {
+            return Ok(inst);
+        }
+        self.pmp_check(self.pc, 32, Access::Fetch)?;
+        match self.bus.fetch(self.pc, 32) {
             Ok(inst) => Ok(inst),
             Err(e) => Err(e),
         }
@@ -194,6 +295,29 @@ impl Cpu {
                 let _rl = (inst >> 25) & 1; // release access
                 let _aq = (inst >> 26) & 1; // acquire access
                 let t = (inst >> 27) & 0x1f;
+                if funct3 == 0b010 || funct3 == 0b011 {
+                    // The region must support the atomic operation,
+                    // otherwise it faults before memory is accessed.
+                    let addr = self.regs[rs1 as usize];
+                    let size = if funct3 == 0b011 { 64 } else { 32 };
+                    // Atomics are never split, whatever the misaligned
+                    // access policy.
+                    if !addr.is_multiple_of(size / 8) {
+                        return Err(match t {
+                            0b00010 => Exception::LoadAddressMisaligned(addr),
+                            _ => Exception::StoreAMOAddressMisaligned(addr),
+                        });
+                    }
+                    match t {
+                        0b00010 => self.bus.check_lrsc(addr, size, false)?,
+                        0b00011 => self.bus.check_lrsc(addr, size, true)?,
+                        0b00001 => self.bus.check_amo(addr, size, AmoSupport::Swap)?,
+                        0b00100 | 0b01100 | 0b01000 => {
+                            self.bus.check_amo(addr, size, AmoSupport::Logical)?
+                        }
+                        _ => self.bus.check_amo(addr, size, AmoSupport::Arithmetic)?,
+                    }
+                }
                 match (funct3, t) {
                     // (0b010, 0b00010) => self.execute_lr_w(inst),
                     // (0b010, 0b00011) => self.execute_sc_w(inst),
@@ -219,7 +343,6 @@ impl Cpu {
                     // (0b011, 0b11100) => self.execute_amomaxu_d(inst),
                     _ => return Err(Exception::IllegalInstruction(inst)),
                 }
-                return Err(Exception::IllegalInstruction(inst));
             }
             (0b01, 0b100) => {
                 // OP
@@ -307,12 +430,12 @@ impl Cpu {
                                 | ((inst >> 20) & 0x7e0) // imm[10:5]
                                 | ((inst >> 7) & 0x1e); // imm[4:1]
                 match funct3 {
-                    0b000 => inst_step = self.execute_beq(rs1, rs2, imm),
-                    0b001 => inst_step = self.execute_bne(rs1, rs2, imm),
-                    0b100 => inst_step = self.execute_blt(rs1, rs2, imm),
-                    0b101 => inst_step = self.execute_bge(rs1, rs2, imm),
-                    0b110 => inst_step = self.execute_bltu(rs1, rs2, imm),
-                    0b111 => inst_step = self.execute_bgeu(rs1, rs2, imm),
+                    0b000 => inst_step = self.execute_beq(rs1, rs2, imm)?,
+                    0b001 => inst_step = self.execute_bne(rs1, rs2, imm)?,
+                    0b100 => inst_step = self.execute_blt(rs1, rs2, imm)?,
+                    0b101 => inst_step = self.execute_bge(rs1, rs2, imm)?,
+                    0b110 => inst_step = self.execute_bltu(rs1, rs2, imm)?,
+                    0b111 => inst_step = self.execute_bgeu(rs1, rs2, imm)?,
                     _ => {
                         return Err(Exception::IllegalInstruction(inst));
                     }
@@ -321,7 +444,7 @@ impl Cpu {
             (0b11, 0b001) => {
                 // JALR
                 let imm = ((((inst & 0xfff00000) as i32) as i64) >> 20) as u64;
-                self.execute_jalr(rd, rs1, imm);
+                self.execute_jalr(rd, rs1, imm)?;
                 inst_step = 0;
             }
             (0b11, 0b010) => {
@@ -335,23 +458,31 @@ impl Cpu {
                     | (inst & 0xff000) // imm[19:12]
                     | ((inst >> 9) & 0x800) // imm[11]
                     | ((inst >> 20) & 0x7fe); // imm[10:1]
-                self.execute_jal(rd, imm);
+                self.execute_jal(rd, imm)?;
                 inst_step = 0;
             }
             (0b11, 0b100) => {
                 // SYSTEM
                 let csr_addr = inst >> 20;
                 let uimm = rs1 as u64;
+                // CSR writes and xRET may change which interrupts are enabled.
+                self.bus.scheduler.mark_dirty();
+                if funct3 & 0b11 != 0 {
+                    // CSRRW and CSRRWI always write the CSR, the other CSR
+                    // instructions only with a nonzero rs1 or uimm.
+                    let write = funct3 & 0b11 == 0b01 || rs1 != 0;
+                    self.check_csr_access(csr_addr, write, inst)?;
+                }
                 match (rs2, funct3) {
                     (0b0, 0b000) => self.execute_ecall()?,
                     (0b1, 0b000) => self.execute_ebreak()?,
                     (0b00010, 0b000) => match funct7 {
                         0b0001000 => {
-                            self.execute_sret();
+                            self.execute_sret(inst)?;
                             inst_step = 0;
                         }
                         0b0011000 => {
-                            self.execute_mret();
+                            self.execute_mret(inst)?;
                             inst_step = 0;
                         }
                         // 0b0111000 => self.execute_mnret(),
@@ -360,8 +491,8 @@ impl Cpu {
                         }
                     },
                     (_, 0b000) => match funct7 {
-                        // 0b0001000 => self.execute_wfi(),
-                        0b0001001 => self.execute_sfence_vma(),
+                        0b0001000 if rs2 == 0b00101 => self.execute_wfi(inst)?,
+                        0b0001001 => self.execute_sfence_vma(inst)?,
                         // 0b0010001 => self.execute_hfence_vvma(),
                         // 0b0110001 => self.execute_hfence_gvma(),
                         _ => {
@@ -400,38 +531,54 @@ impl Cpu {
         Ok(())
     }
 
-    pub fn check_interrupt(&mut self) -> Option<Interrupt> {
+    /// Update the interrupt-pending bits from the state of the devices.
+    ///
+    /// MSIP, MTIP, MEIP and SEIP follow the interrupt lines and are cleared
+    /// once the device stops requesting the interrupt. An ACLINT SSWI write
+    /// to setssip sets SSIP, which software clears. STIP follows stimecmp
+    /// when Sstc is enabled and is left to software otherwise.
+    pub fn update_pending(&mut self) {
         let (mtip, msip) = self.bus.clint.check_interrupts(0);
-        let mut mip_value = self.csr_load(MIP);
-        if mtip {
-            mip_value |= 1 << 7;
-        } else {
-            mip_value &= !(1 << 7);
+        self.csr
+            .set_pending(Interrupt::MachineTimerInterrupt.bit(), mtip);
+        self.csr
+            .set_pending(Interrupt::MachineSoftwareInterrupt.bit(), msip);
+
+        if self.bus.clint.take_ssip(0) {
+            self.csr
+                .set_pending(Interrupt::SupervisorSoftwareInterrupt.bit(), true);
         }
 
-        if msip {
-            mip_value |= 1 << 3;
-        } else {
-            mip_value &= !(1 << 3);
+        // Sstc: when menvcfg.STCE is set, STIP is driven by the stimecmp
+        // comparator, so S-mode timer interrupts are delivered without an
+        // SBI call into M-mode firmware to program mtimecmp.
+        if self.csr_load(MENVCFG) & MENVCFG_STCE != 0 {
+            let stip = self.bus.clint.time() >= self.csr_load(STIMECMP);
+            self.csr
+                .set_pending(Interrupt::SupervisorTimerInterrupt.bit(), stip);
         }
-        self.csr_store(MIP, mip_value);
-
-        // Check if there are any interrupts to be triggered from the UART.
-        // If an interrupt occurs, notify the PLIC
-        self.bus.uart.check_interrupt();
-        // Attempt to claim a pending interrupt from the PLIC.
-        // If a new interrupt is pending,
-        // set the Supervisor External Interrupt Pending bit (SEIP, bit 9) in SIP.
-        // This ensures that the interrupt handling logic in Supervisor mode
-        // can detect and properly handle the interrupt.
-        let irq = self.bus.plic.borrow_mut().claim();
-        match irq {
-            Some(_irq) => {
-                let sip_value = self.csr_load(SIP);
-                self.csr_store(SIP, sip_value | (1 << 9));
-            }
-            _ => {}
+
+        // The PLIC has an M-mode (0) and an S-mode (1) context. The
+        // interrupt itself is claimed by the guest through the claim
+        // register. With the Advanced Interrupt Architecture, external
+        // interrupts come from the IMSIC interrupt files or from APLIC
+        // direct delivery.
+        let (mut meip, mut seip) = self.aia_external_interrupts();
+        if let Some(plic) = &self.bus.plic {
+            let plic = plic.borrow();
+            meip |= plic.check_pending(0).is_some();
+            seip |= plic.check_pending(1).is_some();
         }
+        self.csr
+            .set_pending(Interrupt::MachineExternalInterrupt.bit(), meip);
+        self.csr
+            .set_pending(Interrupt::SupervisorExternalInterrupt.bit(), seip);
+    }
+
+    /// The interrupt to take now, if any. Pending bits are not consumed:
+    /// an interrupt stays pending until its source is cleared.
+    pub fn check_interrupt(&mut self) -> Option<Interrupt> {
+        self.update_pending();
 
         // When a hart is executing in privilege mode x, interrupts are
         // globally enabled when xIE=1 and globally disabled when xIE=0.
@@ -440,239 +587,131 @@ impl Cpu {
         // the lower-privilege mode. Interrupts for higher-privilege modes,
         // y>x, are always globally enabled regardless of the setting of
         // the global yIE bit for the higher-privilege mode.
-        // Higher-privilege-level code can use separate per-interrupt
-        // enable bits to disable selected higher-privilege-mode interrupts
-        // before ceding control to a lower-privilege mode.
-
-        // An interrupt i will trap to M-mode (causing the privilege mode
-        // to change to M-mode) if all of the following are true: (a) either
-        // the current privilege mode is M and the MIE bit in the mstatus
-        // register is set, or the current privilege mode has less privilege
-        // than M-mode; (b) bit i is set in both mip and mie; and (c) if
-        // register mideleg exists, bit i is not set in mideleg.
-
-        // First, check if the hart is in Machine mode and if interrupts are enabled (MIE = 1)
-        if self.mode == Mode::Machine && (self.csr_load(MSTATUS) & (1 << 3) == 0) {
-            // If MIE is 0, no interrupts should be handled in Machine mode
-            return None;
-        }
+        let mstatus = self.csr_load(MSTATUS);
+        let (machine_enabled, supervisor_enabled) = match self.mode {
+            Mode::Machine => (mstatus & (1 << 3) != 0, false),
+            Mode::Supervisor => (true, mstatus & (1 << 1) != 0),
+            Mode::User => (true, true),
+        };
 
-        // Check for any pending interrupts in Machine mode
-        // MIE enables interrupts for Machine mode, and MIP holds the pending interrupts for M-mode
-        let machine_pending = self.csr_load(MIE) & self.csr_load(MIP);
+        // An interrupt i traps to M-mode if bit i is set in both mip and mie
+        // and is not set in mideleg, and to S-mode if it is delegated.
+        let pending = self.csr_load(MIP) & self.csr_load(MIE);
+        let mideleg = self.csr_load(MIDELEG);
 
-        let mip_value = self.csr_load(MIP);
-        if (machine_pending & 1 << 11) != 0 {
-            self.csr_store(MIP, mip_value & !(1 << 11));
-            return Some(Interrupt::MachineExternalInterrupt);
-        }
-        if (machine_pending & 1 << 3) != 0 {
-            self.csr_store(MIP, mip_value & !(1 << 3));
-            return Some(Interrupt::MachineSoftwareInterrupt);
+        // Multiple simultaneous interrupts destined for different privilege
+        // modes are handled in decreasing order of destined privilege mode,
+        // and interrupts for the same mode in the order MEI, MSI, MTI, SEI,
+        // SSI, STI, LCOFI.
+        if machine_enabled {
+            if let Some(interrupt) = Interrupt::highest_priority(pending & !mideleg) {
+                return Some(interrupt);
+            }
         }
-        if (machine_pending & 1 << 7) != 0 {
-            self.csr_store(MIP, mip_value & !(1 << 7));
-            return Some(Interrupt::MachineTimerInterrupt);
+        if supervisor_enabled {
+            return Interrupt::highest_priority(pending & mideleg);
         }
+        None
+    }
 
-        // In Supervisor mode, check if Supervisor interrupts are enabled (SIE = 1)
-        if self.mode == Mode::Supervisor && (self.csr_load(SSTATUS) & (1 << 1) == 0) {
-            // If SIE is 0, no Supervisor interrupts will be handled
-            return None;
-        }
+    /// The privilege mode an exception is taken into. Traps never move to a
+    /// less privileged mode: only exceptions raised in S-mode or U-mode can
+    /// be delegated to S-mode through medeleg.
+    pub fn exception_mode(&self, exception: Exception) -> Mode {
+        self.trap_mode(MEDELEG, exception.code())
+    }
 
-        // Now in Supervisor mode, and Supervisor interrupts are enabled (SIE = 1)
-        // Check for pending Supervisor interrupts by examining SIP (pending) and SIE (enabled)
-        let supervisor_pending = self.csr_load(SIE) & self.csr_load(SIP);
+    /// The privilege mode an interrupt is taken into, following mideleg.
+    pub fn interrupt_mode(&self, interrupt: Interrupt) -> Mode {
+        self.trap_mode(MIDELEG, interrupt.code() & !INTERRUPT_BIT)
+    }
 
-        let sip_value = self.csr_load(SIP);
-        if (supervisor_pending & 1 << 9) != 0 {
-            self.csr_store(SIP, sip_value & !(1 << 9));
-            return Some(Interrupt::SupervisorExternalInterrupt);
-        }
-        if (supervisor_pending & 1 << 1) != 0 {
-            self.csr_store(SIP, sip_value & !(1 << 1));
-            return Some(Interrupt::SupervisorSoftwareInterrupt);
+    fn trap_mode(&self, deleg: u64, cause: u64) -> Mode {
+        let delegated = self.csr_load(deleg).wrapping_shr(cause as u32) & 1 != 0;
+        if self.mode != Mode::Machine && delegated {
+            Mode::Supervisor
+        } else {
+            Mode::Machine
         }
-        if (supervisor_pending & 1 << 5) != 0 {
-            self.csr_store(SIP, sip_value & !(1 << 5));
-            return Some(Interrupt::SupervisorTimerInterrupt);
+    }
+
+    /// The value written to xtval for an exception: the faulting address
+    /// for misaligned addresses, access faults and page faults, the pc for
+    /// breakpoints and the instruction bits for illegal instructions.
+    /// Environment calls and hardware errors write zero.
+    pub fn trap_value(exception: Exception) -> u64 {
+        match exception {
+            Exception::EnvironmentCallFromUMode(_)
+            | Exception::EnvironmentCallFromSMode(_)
+            | Exception::EnvironmentCallFromMMode(_)
+            | Exception::HardwareError(_) => 0,
+            _ => exception.value(),
         }
-        // If no interrupt, return None
-        return None;
     }
 
     pub fn handle_exception(&mut self, exception: Exception) {
-        let exception_pc = self.pc;
-        let prev_mode = self.mode;
-        let cause = exception.code();
-
-        let is_user_or_supervisor = prev_mode != Mode::Machine;
-        let medeleg = self.csr_load(MEDELEG);
-        let is_exception_delegated = (medeleg.wrapping_shr(cause as u32) & 1) != 0;
-        if is_user_or_supervisor && is_exception_delegated {
-            self.mode = Mode::Supervisor;
-        }
-        // 1.
-        // The mtvec register must always be implemented, but can contain a
-        // read-only value. If mtvec is writable, the set of values the register
-        // may hold can vary by implementation. The value in the BASE field
-        // must always be aligned on a 4-byte boundary, and the MODE setting
-        // may impose additional alignment constraints on the value in the
-        // BASE field.
-        // The mtvec register is an MXLEN-bit WARL read/write register that
-        // holds trap vector configuration, consisting of a vector base
-        // address (BASE) and a vector mode (MODE).
-        // When MODE=Direct, all traps into machine mode cause the pc to be
-        // set to the address in the BASE field. When MODE=Vectored, all
-        // synchronous exceptions into machine mode cause the pc to be set
-        // to the address in the BASE field, whereas interrupts cause the pc
-        // to be set to the address in the BASE field plus four times the
-        // interrupt cause number.
-        // 2.
-        // When a trap is taken into M-mode, mepc is written with the
-        // virtual address of the instruction that was interrupted or that
-        // encountered the exception. Otherwise, mepc is never written by the
-        // implementation, though it may be explicitly written by software
-        // 3.
-        // When a trap is taken into M-mode, mcause is written with a code
-        // indicating the event that caused the trap. Otherwise, mcause is
-        // never written by the implementation, though it may be explicitly
-        // written by software.
-        // 4.
-        // When a trap is taken into M-mode, mtval is either set to zero or
-        // written with exception-specific information to assist software in
-        // handling the trap. Otherwise, mtval is never written by the
-        // implementation, though it may be explicitly written by software.
-        // 5.
-        // When a trap is taken from privilege mode y into privilege mode x,
-        // xPIE is set to the value of xIE; xIE is set to 0; and xPP is set to y.
-        match self.mode {
-            Mode::Machine => {
-                let mtvec = self.csr_load(MTVEC);
-                let base = mtvec & !0b11;
-                let _mode = mtvec & 0b11;
-                // When MODE=Direct, all traps into machine mode cause the pc to be
-                // set to the address in the BASE field. When MODE=Vectored, all
-                // synchronous exceptions into machine mode cause the pc to be set
-                // to the address in the BASE field, whereas interrupts cause the pc
-                // to be set to the address in the BASE field plus four times the
-                // interrupt cause number.
-                self.pc = base;
-                self.csr_store(MEPC, exception_pc as u64);
-                self.csr_store(MCAUSE, cause);
-                self.csr_store(MTVAL, exception.code());
-                let mut mstatus = self.csr_load(MSTATUS);
-                let mie = (mstatus >> 3) & 0b1;
-                // set xIE = 0
-                mstatus &= !(1 << 3);
-                // set xPIE = previous xIE
-                mstatus &= !(1 << 7);
-                mstatus |= mie << 7;
-                // set xPP = previous mode
-                mstatus &= !(0b11 << 11);
-                mstatus |= prev_mode.code() << 11;
-                self.csr_store(MSTATUS, mstatus);
-            }
-            Mode::Supervisor => {
-                let stvec = self.csr_load(STVEC);
-                let base = stvec & !0b11;
-                let _mode = stvec & 0b11;
-                self.pc = base;
-                self.csr_store(SEPC, exception_pc as u64);
-                self.csr_store(SCAUSE, cause);
-                self.csr_store(STVAL, exception.code());
-                let mut sstatus = self.csr_load(SSTATUS);
-                let sie = (sstatus >> 1) & 0b1;
-                // set xIE = 0
-                sstatus &= !(1 << 1);
-                // set xPIE = previous xIE
-                sstatus &= !(1 << 5);
-                sstatus |= sie << 5;
-                // set xPP = previous mode
-                sstatus &= !(0b1 << 8);
-                sstatus |= prev_mode.code() << 8;
-                self.csr_store(SSTATUS, sstatus);
-            }
-            _ => {}
-        }
+        let mode = self.exception_mode(exception);
+        self.take_trap(mode, exception.code(), Self::trap_value(exception));
     }
 
     pub fn handle_interrupt(&mut self, interrupt: Interrupt) {
-        let interrupt_pc = self.pc;
+        let mode = self.interrupt_mode(interrupt);
+        self.take_trap(mode, interrupt.code(), 0);
+    }
+
+    // Enter the trap handler of `mode` for the trap with mcause/scause value
+    // `cause`.
+    //
+    // When MODE=Direct, all traps into machine mode cause the pc to be set
+    // to the address in the BASE field. When MODE=Vectored, all synchronous
+    // exceptions into machine mode cause the pc to be set to the address in
+    // the BASE field, whereas interrupts cause the pc to be set to the
+    // address in the BASE field plus four times the interrupt cause number.
+    // The reserved MODE values behave as Direct here.
+    //
+    // When a trap is taken from privilege mode y into privilege mode x,
+    // xPIE is set to the value of xIE; xIE is set to 0; and xPP is set to y.
+    fn take_trap(&mut self, mode: Mode, cause: u64, tval: u64) {
+        self.hpm_event(HpmEvent::Traps);
+        let (tvec, epc, xcause, xtval) = match mode {
+            Mode::Machine => (MTVEC, MEPC, MCAUSE, MTVAL),
+            _ => (STVEC, SEPC, SCAUSE, STVAL),
+        };
+        let trap_pc = self.pc;
         let prev_mode = self.mode;
-        let cause = interrupt.code();
-        let cause_code = cause & !INTERRUPT_BIT;
+        let tvec = self.csr_load(tvec);
+        let base = tvec & !0b11;
+        let vectored = tvec & 0b11 == 0b01 && cause & INTERRUPT_BIT != 0;
+        self.pc = if vectored {
+            base.wrapping_add((cause & !INTERRUPT_BIT) << 2)
+        } else {
+            base
+        };
+        self.csr_store(epc, trap_pc);
+        self.csr_store(xcause, cause);
+        self.csr_store(xtval, tval);
 
-        let is_user_or_supervisor = prev_mode != Mode::Machine;
-        let mideleg = self.csr_load(MIDELEG);
-        let is_interrupt_delegated = (mideleg.wrapping_shr(cause_code as u32) & 1) != 0;
-        if is_user_or_supervisor && is_interrupt_delegated {
-            self.mode = Mode::Supervisor;
-        }
-        match self.mode {
+        let mut mstatus = self.csr_load(MSTATUS);
+        match mode {
             Mode::Machine => {
-                let mtvec = self.csr_load(MTVEC);
-                let base = mtvec & !0b11;
-                let mode = mtvec & 0b11;
-                // When MODE=Direct, all traps into machine mode cause the pc to be
-                // set to the address in the BASE field. When MODE=Vectored, all
-                // synchronous exceptions into machine mode cause the pc to be set
-                // to the address in the BASE field, whereas interrupts cause the pc
-                // to be set to the address in the BASE field plus four times the
-                // interrupt cause number.
-                self.pc = match mode {
-                    // Direct
-                    0b00 => base,
-                    // Vectored
-                    0b01 => base + (cause_code << 2),
-                    _ => unreachable!(),
-                };
-                self.csr_store(MEPC, interrupt_pc as u64);
-                self.csr_store(MCAUSE, cause);
-                // When a trap is taken into M-mode, mtval is either set to zero or
-                // written with exception-specific information to assist software in
-                // handling the trap.
-                self.csr_store(MTVAL, 0);
-                let mut mstatus = self.csr_load(MSTATUS);
+                // Trapped instructions are not transformed into mtinst,
+                // which always reads as zero.
+                self.csr_store(MTINST, 0);
                 let mie = (mstatus >> 3) & 0b1;
-                // set xIE = 0
-                mstatus &= !(1 << 3);
-                // set xPIE = previous xIE
-                mstatus &= !(1 << 7);
-                mstatus |= mie << 7;
-                // set xPP = previous mode
-                mstatus &= !(0b11 << 11);
-                mstatus |= prev_mode.code() << 11;
-                self.csr_store(MSTATUS, mstatus);
-            }
-            Mode::Supervisor => {
-                let stvec = self.csr_load(STVEC);
-                let base = stvec & !0b11;
-                let mode = stvec & 0b11;
-                self.pc = match mode {
-                    // Direct
-                    0b00 => base,
-                    // Vectored
-                    0b01 => base + (cause_code << 2),
-                    _ => unreachable!(),
-                };
-                self.csr_store(SEPC, interrupt_pc as u64);
-                self.csr_store(SCAUSE, cause);
-                self.csr_store(STVAL, 0);
-                let mut sstatus = self.csr_load(SSTATUS);
-                let sie = (sstatus >> 1) & 0b1;
-                // set xIE = 0
-                sstatus &= !(1 << 1);
-                // set xPIE = previous xIE
-                sstatus &= !(1 << 5);
-                sstatus |= sie << 5;
-                // set xPP = previous mode
-                sstatus &= !(0b1 << 8);
-                sstatus |= prev_mode.code() << 8;
-                self.csr_store(SSTATUS, sstatus);
-            }
-            _ => {}
+                // set MPIE = MIE, MIE = 0 and MPP = previous mode
+                mstatus &= !((1 << 7) | (1 << 3) | (0b11 << 11));
+                mstatus |= (mie << 7) | (prev_mode.code() << 11);
+            }
+            _ => {
+                let sie = (mstatus >> 1) & 0b1;
+                // set SPIE = SIE, SIE = 0 and SPP = previous mode
+                mstatus &= !((1 << 5) | (1 << 1) | (1 << 8));
+                mstatus |= (sie << 5) | ((prev_mode == Mode::Supervisor) as u64) << 8;
+            }
         }
+        self.csr_store(MSTATUS, mstatus);
+        self.mode = mode;
+        // Entering a trap handler changes the interrupt enables.
+        self.bus.scheduler.mark_dirty();
     }
 }
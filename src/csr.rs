@@ -2,6 +2,49 @@ use crate::lib::address::*;
 
 const NUM_CSRS: usize = 4096;
 
+/// mstatus.UXL and mstatus.SXL set to 64 bits.
+pub const MSTATUS_UXL_64: u64 = 2 << 32;
+pub const MSTATUS_SXL_64: u64 = 2 << 34;
+/// mstatus.MPRV: loads and stores use the privilege mode in MPP.
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+/// mstatus.TVM: trap virtual memory, traps satp accesses and SFENCE.VMA
+/// executed in S-mode.
+pub const MSTATUS_TVM: u64 = 1 << 20;
+/// mstatus.TW: timeout wait, traps WFI executed below M-mode.
+pub const MSTATUS_TW: u64 = 1 << 21;
+/// mstatus.TSR: trap SRET executed in S-mode.
+pub const MSTATUS_TSR: u64 = 1 << 22;
+
+/// menvcfg.STCE: enables the Sstc extension (stimecmp and direct STIP).
+pub const MENVCFG_STCE: u64 = 1 << 63;
+
+/// The mstatus fields visible through sstatus: SIE, SPIE, UBE, SPP, VS,
+/// FS, XS, SUM, MXR, UXL and SD.
+pub const SSTATUS_MASK: u64 = (1 << 63)
+    | (0b11 << 32)
+    | (1 << 19)
+    | (1 << 18)
+    | (0b11 << 15)
+    | (0b11 << 13)
+    | (0b11 << 9)
+    | (1 << 8)
+    | (1 << 6)
+    | (1 << 5)
+    | (1 << 1);
+// The sstatus fields software can write; UXL and SD are read-only.
+const SSTATUS_WRITABLE: u64 = SSTATUS_MASK & !(1 << 63) & !(0b11 << 32);
+
+/// The major interrupts in mip/mie: SSI, MSI, STI, MTI, SEI, MEI and LCOFI.
+pub const MAJOR_INTERRUPTS: u64 =
+    (1 << 1) | (1 << 3) | (1 << 5) | (1 << 7) | (1 << 9) | (1 << 11) | (1 << 13);
+/// The interrupts that can be delegated to S-mode through mideleg.
+pub const SUPERVISOR_INTERRUPTS: u64 = (1 << 1) | (1 << 5) | (1 << 9) | (1 << 13);
+// The mip bits software can write: SSIP, STIP and LCOFIP. The other bits
+// follow the interrupt lines of the devices.
+const MIP_WRITABLE: u64 = (1 << 1) | (1 << 5) | (1 << 13);
+// The sip bits software can write, when delegated: SSIP and LCOFIP.
+const SIP_WRITABLE: u64 = (1 << 1) | (1 << 13);
+
 pub struct Csr {
     csrs: [u64; NUM_CSRS],
 }
@@ -14,10 +57,72 @@ impl Csr {
     }
 
     pub fn load(&self, addr: u64) -> u64 {
+        // sstatus is a restricted view of mstatus.
+        if addr == SSTATUS {
+            return self.csrs[MSTATUS as usize] & SSTATUS_MASK;
+        }
+        // sip and sie show the interrupts delegated to S-mode.
+        match addr {
+            SIP => return self.csrs[MIP as usize] & self.csrs[MIDELEG as usize],
+            SIE => return self.csrs[MIE as usize] & self.csrs[MIDELEG as usize],
+            _ => {}
+        }
         return self.csrs[addr as usize];
     }
 
     pub fn store(&mut self, addr: u64, value: u64) {
-        self.csrs[addr as usize] = value;
+        if addr == SSTATUS {
+            let mstatus = self.csrs[MSTATUS as usize];
+            self.csrs[MSTATUS as usize] =
+                (mstatus & !SSTATUS_WRITABLE) | (value & SSTATUS_WRITABLE);
+            return;
+        }
+        if addr == MSTATUS {
+            // MPP is WARL: writing a mode that is not implemented keeps the
+            // previous value.
+            let misa = self.csrs[MISA as usize];
+            let mpp = (value >> 11) & 0b11;
+            let implemented = match mpp {
+                0b11 => true,
+                0b01 => misa & (1 << ('s' as u64 - 'a' as u64)) != 0,
+                0b00 => misa & (1 << ('u' as u64 - 'a' as u64)) != 0,
+                _ => false,
+            };
+            if !implemented {
+                let mstatus = self.csrs[MSTATUS as usize];
+                self.csrs[MSTATUS as usize] = (value & !(0b11 << 11)) | (mstatus & (0b11 << 11));
+                return;
+            }
+        }
+        // mip, mie and mideleg only implement the major interrupts, and sip
+        // and sie write the delegated bits of mip and mie. misa is read-only,
+        // so the guest cannot enable extensions that are not implemented.
+        let mideleg = self.csrs[MIDELEG as usize];
+        let (addr, writable) = match addr {
+            MISA => (MISA, 0),
+            MIP => (MIP, MIP_WRITABLE),
+            MIE => (MIE, MAJOR_INTERRUPTS),
+            MIDELEG => (MIDELEG, SUPERVISOR_INTERRUPTS),
+            SIP => (MIP, SIP_WRITABLE & mideleg),
+            SIE => (MIE, mideleg),
+            _ => (addr, !0),
+        };
+        let old = self.csrs[addr as usize];
+        self.csrs[addr as usize] = (old & !writable) | (value & writable);
+    }
+
+    /// Set misa, which software cannot write.
+    pub fn set_misa(&mut self, misa: u64) {
+        self.csrs[MISA as usize] = misa;
+    }
+
+    /// Set or clear the interrupt-pending bits `mask` in mip, including the
+    /// bits software cannot write.
+    pub fn set_pending(&mut self, mask: u64, pending: bool) {
+        if pending {
+            self.csrs[MIP as usize] |= mask;
+        } else {
+            self.csrs[MIP as usize] &= !mask;
+        }
     }
 }
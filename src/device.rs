@@ -0,0 +1,177 @@
+use crate::exception::*;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+/// A memory-mapped device.
+///
+/// Accesses are relative to the base address the device is mapped at and
+/// `size` is the access width in bits. A device reports unsupported accesses
+/// with an access fault; the bus replaces the offset with the full address.
+pub trait Device {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception>;
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception>;
+
+    /// Physical memory attributes of the region the device is mapped at.
+    fn pma(&self) -> Pma {
+        Pma::io(WIDTH_32)
+    }
+
+    /// Host memory backing the page that holds `offset`, for devices that
+    /// are plain memory. Accesses through it must have no side effects.
+    /// `write` is set when the page is wanted for stores.
+    fn host_page(&mut self, _offset: u64, _write: bool) -> Option<HostPage> {
+        None
+    }
+
+    /// Return the device to its power-on state.
+    fn reset(&mut self) {}
+
+    /// Advance the device to mtime `now`.
+    fn tick(&mut self, _now: u64) {}
+
+    /// Poll the device for new interrupt conditions and update its
+    /// interrupt lines.
+    fn check_interrupt(&mut self) {}
+}
+
+// Access widths, as a mask of the widths a region supports.
+pub const WIDTH_8: u8 = 1 << 0;
+pub const WIDTH_16: u8 = 1 << 1;
+pub const WIDTH_32: u8 = 1 << 2;
+pub const WIDTH_64: u8 = 1 << 3;
+pub const WIDTHS_ALL: u8 = WIDTH_8 | WIDTH_16 | WIDTH_32 | WIDTH_64;
+
+/// Classes of AMOs a region supports. Each class includes the ones
+/// before it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AmoSupport {
+    None,
+    /// AMOSWAP only.
+    Swap,
+    /// AMOSWAP and the logical AMOs (AMOAND, AMOOR, AMOXOR).
+    Logical,
+    /// All AMOs.
+    Arithmetic,
+}
+
+/// Physical memory attributes of a region.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Pma {
+    /// Supported access widths, see WIDTH_*.
+    pub widths: u8,
+    pub cacheable: bool,
+    /// Accesses have no side effects and may be repeated.
+    pub idempotent: bool,
+    pub executable: bool,
+    pub amo: AmoSupport,
+    /// LR/SC reservations are supported.
+    pub lrsc: bool,
+}
+
+impl Pma {
+    /// Main memory: any width, cacheable, executable and atomic.
+    pub fn memory() -> Self {
+        Self {
+            widths: WIDTHS_ALL,
+            cacheable: true,
+            idempotent: true,
+            executable: true,
+            amo: AmoSupport::Arithmetic,
+            lrsc: true,
+        }
+    }
+
+    /// Device registers accessed with the given widths.
+    pub fn io(widths: u8) -> Self {
+        Self {
+            widths,
+            cacheable: false,
+            idempotent: false,
+            executable: false,
+            amo: AmoSupport::None,
+            lrsc: false,
+        }
+    }
+
+    /// Whether an access of `size` bits is supported.
+    pub fn allows_width(&self, size: u64) -> bool {
+        let width = match size {
+            8 => WIDTH_8,
+            16 => WIDTH_16,
+            32 => WIDTH_32,
+            64 => WIDTH_64,
+            _ => 0,
+        };
+        self.widths & width != 0
+    }
+}
+
+/// A page of guest memory that can be accessed directly through a host
+/// pointer. The memory stays valid for as long as the device is mapped.
+#[derive(Debug, Copy, Clone)]
+pub struct HostPage {
+    /// Address of the first byte of the page, relative to the device before
+    /// the bus relocates it.
+    pub base: u64,
+    pub len: u64,
+    pub ptr: *mut u8,
+}
+
+impl HostPage {
+    pub fn contains(&self, addr: u64, size: u64) -> bool {
+        self.base <= addr && addr - self.base + size / 8 <= self.len
+    }
+}
+
+/// A device mapped into the physical address space.
+pub struct Region {
+    pub name: String,
+    pub base: u64,
+    pub size: u64,
+    pub pma: Pma,
+    pub device: Rc<RefCell<dyn Device>>,
+}
+
+impl Region {
+    /// A region with the attributes the device reports.
+    pub fn new(name: String, base: u64, size: u64, device: Rc<RefCell<dyn Device>>) -> Self {
+        let pma = device.borrow().pma();
+        Self {
+            name,
+            base,
+            size,
+            pma,
+            device,
+        }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.base + self.size
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.base <= addr && addr < self.end()
+    }
+}
+
+/// A region could not be added to the address map.
+#[derive(Debug)]
+pub enum MapError {
+    /// The region has no size or wraps around the address space.
+    Invalid { name: String },
+    /// The region overlaps a region that is already mapped.
+    Overlap { name: String, existing: String },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::Invalid { name } => write!(f, "invalid address range for {}", name),
+            MapError::Overlap { name, existing } => {
+                write!(f, "{} overlaps the region of {}", name, existing)
+            }
+        }
+    }
+}
@@ -1,38 +1,172 @@
+use crate::device::{Device, HostPage, Pma};
 use crate::exception::*;
-use crate::lib::address::*;
+
+/// Guest RAM is allocated in pages of this many bytes when first written.
+pub const DRAM_PAGE_SIZE: u64 = 0x10000;
 
 pub struct Dram {
-    pub dram: Vec<u8>,
+    size: u64,
+    // Host memory backing each page. Pages that were never written are
+    // not allocated and read as zero.
+    pages: Vec<Option<Box<[u8]>>>,
+    // Images loaded at power-on, written again on every reset.
+    images: Vec<(u64, Vec<u8>)>,
 }
 
 impl Dram {
-    pub fn new(binary: Vec<u8>) -> Self {
-        let mut dram = vec![0; DRAM_SIZE as usize];
-        dram.splice(..binary.len(), binary.iter().cloned());
+    pub fn new(size: u64, binary: Vec<u8>) -> Self {
+        let count = size.div_ceil(DRAM_PAGE_SIZE);
+        let mut dram = Self {
+            size,
+            pages: (0..count).map(|_| None).collect(),
+            images: Vec::new(),
+        };
+        dram.load_image(0, binary, "The binary");
+        dram
+    }
+
+    /// Place an image at `offset`, now and after every reset. `what` names
+    /// the image if it does not fit.
+    pub fn load_image(&mut self, offset: u64, image: Vec<u8>, what: &str) {
+        assert!(
+            offset + image.len() as u64 <= self.size,
+            "{} does not fit in memory",
+            what
+        );
+        if let Some((start, other)) = self.images.iter().find(|(start, other)| {
+            offset < start + other.len() as u64 && *start < offset + image.len() as u64
+        }) {
+            panic!(
+                "{} overlaps the image at offset {:#x} ({} bytes)",
+                what,
+                start,
+                other.len()
+            );
+        }
+        self.write_bytes(offset, &image);
+        self.images.push((offset, image));
+    }
+
+    fn page_mut(&mut self, index: usize) -> &mut [u8] {
+        self.pages[index].get_or_insert_with(|| vec![0; DRAM_PAGE_SIZE as usize].into_boxed_slice())
+    }
+
+    fn read_byte(&self, offset: u64) -> u8 {
+        match &self.pages[(offset / DRAM_PAGE_SIZE) as usize] {
+            Some(page) => page[(offset % DRAM_PAGE_SIZE) as usize],
+            None => 0,
+        }
+    }
+
+    fn write_byte(&mut self, offset: u64, value: u8) {
+        let page = self.page_mut((offset / DRAM_PAGE_SIZE) as usize);
+        page[(offset % DRAM_PAGE_SIZE) as usize] = value;
+    }
+
+    /// Copy `data` to memory at `offset`, which must be in range.
+    pub fn write_bytes(&mut self, offset: u64, data: &[u8]) {
+        let mut offset = offset;
+        let mut data = data;
+        while !data.is_empty() {
+            let start = (offset % DRAM_PAGE_SIZE) as usize;
+            let len = data.len().min(DRAM_PAGE_SIZE as usize - start);
+            let page = self.page_mut((offset / DRAM_PAGE_SIZE) as usize);
+            page[start..start + len].copy_from_slice(&data[..len]);
+            offset += len as u64;
+            data = &data[len..];
+        }
+    }
+}
 
-        Self { dram }
+/// Read a little-endian value of `size` bits from the start of `bytes`.
+pub fn read_le(bytes: &[u8], size: u64) -> u64 {
+    match size {
+        8 => bytes[0] as u64,
+        16 => u16::from_le_bytes(bytes[..2].try_into().unwrap()) as u64,
+        32 => u32::from_le_bytes(bytes[..4].try_into().unwrap()) as u64,
+        _ => u64::from_le_bytes(bytes[..8].try_into().unwrap()),
     }
+}
+
+/// Write a little-endian value of `size` bits to the start of `bytes`.
+pub fn write_le(bytes: &mut [u8], size: u64, value: u64) {
+    match size {
+        8 => bytes[0] = value as u8,
+        16 => bytes[..2].copy_from_slice(&(value as u16).to_le_bytes()),
+        32 => bytes[..4].copy_from_slice(&(value as u32).to_le_bytes()),
+        _ => bytes[..8].copy_from_slice(&value.to_le_bytes()),
+    }
+}
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if size % 8 != 0 {
-            return Err(Exception::LoadAccessFault(addr));
+impl Device for Dram {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if !matches!(size, 8 | 16 | 32 | 64) || offset + size / 8 > self.size {
+            return Err(Exception::LoadAccessFault(offset));
         }
-        let index = (addr - DRAM_BASE) as usize;
-        let mut value = self.dram[index] as u64;
-        for i in 1..size / 8 {
-            value |= (self.dram[index + i as usize] as u64) << (i * 8);
+        let start = (offset % DRAM_PAGE_SIZE) as usize;
+        if start + (size / 8) as usize <= DRAM_PAGE_SIZE as usize {
+            return Ok(match &self.pages[(offset / DRAM_PAGE_SIZE) as usize] {
+                Some(page) => read_le(&page[start..], size),
+                None => 0,
+            });
+        }
+        // The access spans two pages.
+        let mut value = 0;
+        for i in 0..size / 8 {
+            value |= (self.read_byte(offset + i) as u64) << (i * 8);
         }
         Ok(value)
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if size % 8 != 0 {
-            return Err(Exception::StoreAMOAccessFault(addr));
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if !matches!(size, 8 | 16 | 32 | 64) || offset + size / 8 > self.size {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        let start = (offset % DRAM_PAGE_SIZE) as usize;
+        if start + (size / 8) as usize <= DRAM_PAGE_SIZE as usize {
+            let page = self.page_mut((offset / DRAM_PAGE_SIZE) as usize);
+            write_le(&mut page[start..], size, value);
+            return Ok(());
         }
-        let index = (addr - DRAM_BASE) as usize;
         for i in 0..size / 8 {
-            self.dram[index + i as usize] = (value >> (i * 8) & 0xff) as u8;
+            self.write_byte(offset + i, (value >> (i * 8) & 0xff) as u8);
         }
         Ok(())
     }
+
+    // Only stores allocate a page: loads and fetches from a page that was
+    // never written take the bus and read zero.
+    fn host_page(&mut self, offset: u64, write: bool) -> Option<HostPage> {
+        if offset >= self.size {
+            return None;
+        }
+        let base = offset - offset % DRAM_PAGE_SIZE;
+        let len = DRAM_PAGE_SIZE.min(self.size - base);
+        let index = (offset / DRAM_PAGE_SIZE) as usize;
+        let page = match &mut self.pages[index] {
+            Some(page) => page,
+            None if write => self.page_mut(index),
+            None => return None,
+        };
+        Some(HostPage {
+            base,
+            len,
+            ptr: page.as_mut_ptr(),
+        })
+    }
+
+    fn pma(&self) -> Pma {
+        Pma::memory()
+    }
+
+    // Pages stay allocated so that host pointers to them remain valid.
+    fn reset(&mut self) {
+        for page in self.pages.iter_mut().flatten() {
+            page.fill(0);
+        }
+        for (offset, image) in std::mem::take(&mut self.images) {
+            self.write_bytes(offset, &image);
+            self.images.push((offset, image));
+        }
+    }
 }
@@ -61,15 +61,50 @@ impl Exception {
         }
     }
 
-    pub fn is_fatal(self) -> bool {
+    /// The kebab-case name of the exception, as used on the command line.
+    pub const fn name(self) -> &'static str {
         match self {
-            Exception::InstructionAddressMisaligned(_)
-            | Exception::InstructionAccessFault(_)
-            | Exception::LoadAccessFault(_)
-            | Exception::StoreAMOAddressMisaligned(_)
-            | Exception::StoreAMOAccessFault(_)
-            | Exception::IllegalInstruction(_) => true,
-            _else => false,
+            Exception::InstructionAddressMisaligned(_) => "instruction-address-misaligned",
+            Exception::InstructionAccessFault(_) => "instruction-access-fault",
+            Exception::IllegalInstruction(_) => "illegal-instruction",
+            Exception::Breakpoint(_) => "breakpoint",
+            Exception::LoadAddressMisaligned(_) => "load-address-misaligned",
+            Exception::LoadAccessFault(_) => "load-access-fault",
+            Exception::StoreAMOAddressMisaligned(_) => "store-address-misaligned",
+            Exception::StoreAMOAccessFault(_) => "store-access-fault",
+            Exception::EnvironmentCallFromUMode(_) => "ecall-from-u-mode",
+            Exception::EnvironmentCallFromSMode(_) => "ecall-from-s-mode",
+            Exception::EnvironmentCallFromMMode(_) => "ecall-from-m-mode",
+            Exception::InstructionPageFault(_) => "instruction-page-fault",
+            Exception::LoadPageFault(_) => "load-page-fault",
+            Exception::StoreAMOPageFault(_) => "store-page-fault",
+            Exception::SoftwareCheck(_) => "software-check",
+            Exception::HardwareError(_) => "hardware-error",
         }
     }
+
+    /// The cause code of the exception called `name`.
+    pub fn code_from_name(name: &str) -> Option<u64> {
+        let all = [
+            Exception::InstructionAddressMisaligned(0),
+            Exception::InstructionAccessFault(0),
+            Exception::IllegalInstruction(0),
+            Exception::Breakpoint(0),
+            Exception::LoadAddressMisaligned(0),
+            Exception::LoadAccessFault(0),
+            Exception::StoreAMOAddressMisaligned(0),
+            Exception::StoreAMOAccessFault(0),
+            Exception::EnvironmentCallFromUMode(0),
+            Exception::EnvironmentCallFromSMode(0),
+            Exception::EnvironmentCallFromMMode(0),
+            Exception::InstructionPageFault(0),
+            Exception::LoadPageFault(0),
+            Exception::StoreAMOPageFault(0),
+            Exception::SoftwareCheck(0),
+            Exception::HardwareError(0),
+        ];
+        all.iter()
+            .find(|exception| exception.name() == name)
+            .map(|exception| exception.code())
+    }
 }
@@ -0,0 +1,111 @@
+use crate::device::{AmoSupport, Device, HostPage, Pma};
+use crate::dram::*;
+use crate::exception::*;
+use crate::machine::FileMapping;
+use memmap2::{Mmap, MmapMut, MmapOptions};
+use std::fs::OpenOptions;
+use std::io;
+
+enum Mapping {
+    Writable(MmapMut),
+    ReadOnly(Mmap),
+}
+
+/// Guest memory mapped from a host file.
+pub struct FileMemory {
+    map: Mapping,
+}
+
+impl FileMemory {
+    /// Map the first `size` bytes of the file at `path`. A shared mapping
+    /// grows the file to `size` bytes, the other mappings need a file at
+    /// least that large.
+    pub fn open(path: &str, size: u64, mapping: FileMapping) -> io::Result<Self> {
+        let shared = mapping == FileMapping::Shared;
+        let file = OpenOptions::new().read(true).write(shared).open(path)?;
+        let len = file.metadata()?.len();
+        if len < size {
+            if !shared {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("the file is smaller than its region ({} bytes)", size),
+                ));
+            }
+            file.set_len(size)?;
+        }
+        let mut options = MmapOptions::new();
+        options.len(size as usize);
+        // SAFETY: the mapping is only accessed through this device. Other
+        // processes may change a shared file under us, which is the purpose
+        // of sharing it.
+        let map = unsafe {
+            match mapping {
+                FileMapping::Private => Mapping::Writable(options.map_copy(&file)?),
+                FileMapping::Shared => Mapping::Writable(options.map_mut(&file)?),
+                FileMapping::ReadOnly => Mapping::ReadOnly(options.map(&file)?),
+            }
+        };
+        Ok(Self { map })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.map {
+            Mapping::Writable(map) => map,
+            Mapping::ReadOnly(map) => map,
+        }
+    }
+}
+
+impl Device for FileMemory {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        let bytes = self.bytes();
+        if !matches!(size, 8 | 16 | 32 | 64) || offset + size / 8 > bytes.len() as u64 {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        Ok(read_le(&bytes[offset as usize..], size))
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let map = match &mut self.map {
+            Mapping::Writable(map) => map,
+            Mapping::ReadOnly(_) => return Err(Exception::StoreAMOAccessFault(offset)),
+        };
+        if !matches!(size, 8 | 16 | 32 | 64) || offset + size / 8 > map.len() as u64 {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        write_le(&mut map[offset as usize..], size, value);
+        Ok(())
+    }
+
+    // Read-only mappings always take the bus, which rejects the writes.
+    fn host_page(&mut self, offset: u64, _write: bool) -> Option<HostPage> {
+        let map = match &mut self.map {
+            Mapping::Writable(map) => map,
+            Mapping::ReadOnly(_) => return None,
+        };
+        let size = map.len() as u64;
+        if offset >= size {
+            return None;
+        }
+        let base = offset - offset % DRAM_PAGE_SIZE;
+        Some(HostPage {
+            base,
+            len: DRAM_PAGE_SIZE.min(size - base),
+            // SAFETY: base is within the mapping.
+            ptr: unsafe { map.as_mut_ptr().add(base as usize) },
+        })
+    }
+
+    // Read-only mappings cannot be written, so they support neither AMOs
+    // nor LR/SC: an AMO faults before it has read anything.
+    fn pma(&self) -> Pma {
+        match self.map {
+            Mapping::Writable(_) => Pma::memory(),
+            Mapping::ReadOnly(_) => Pma {
+                amo: AmoSupport::None,
+                lrsc: false,
+                ..Pma::memory()
+            },
+        }
+    }
+}
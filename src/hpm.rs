@@ -0,0 +1,204 @@
+use crate::cpu::Mode;
+use crate::lib::address::*;
+
+// mhpmevent fields (Sscofpmf).
+/// Overflow: set when the counter overflows, which raises a local
+/// counter-overflow interrupt unless the bit was already set.
+pub const MHPMEVENT_OF: u64 = 1 << 63;
+/// Inhibit counting in M-mode.
+pub const MHPMEVENT_MINH: u64 = 1 << 62;
+/// Inhibit counting in S-mode.
+pub const MHPMEVENT_SINH: u64 = 1 << 61;
+/// Inhibit counting in U-mode.
+pub const MHPMEVENT_UINH: u64 = 1 << 60;
+/// The event selector.
+pub const MHPMEVENT_EVENT: u64 = 0xff;
+
+const MHPMEVENT_WRITABLE: u64 =
+    MHPMEVENT_OF | MHPMEVENT_MINH | MHPMEVENT_SINH | MHPMEVENT_UINH | MHPMEVENT_EVENT;
+
+// mcountinhibit.TM does not exist: time is not a hart counter.
+const MCOUNTINHIBIT_WRITABLE: u64 = !0b10 & 0xffff_ffff;
+
+/// Events the performance-monitoring counters can count, selected by the
+/// value of mhpmevent. Other event numbers are accepted but never count.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HpmEvent {
+    /// Every retired instruction.
+    Instructions = 0x01,
+    /// Retired integer computational instructions other than multiplies
+    /// and divides.
+    IntegerInstructions = 0x02,
+    /// Retired multiplies and divides.
+    MulDivInstructions = 0x03,
+    /// Retired atomic memory operations, LR and SC.
+    AtomicInstructions = 0x04,
+    /// Retired CSR, environment, fence and other system instructions.
+    SystemInstructions = 0x05,
+    /// Retired loads.
+    Loads = 0x06,
+    /// Retired stores.
+    Stores = 0x07,
+    /// Retired conditional branches.
+    Branches = 0x08,
+    /// Retired JAL and JALR.
+    Jumps = 0x09,
+    /// Exceptions and interrupts taken.
+    Traps = 0x0a,
+    /// Instruction and data TLB misses. Address translation is out of
+    /// scope for this emulator: there are no page-table walks to count,
+    /// so counters selecting these events never advance.
+    #[allow(dead_code)]
+    ItlbMisses = 0x0b,
+    #[allow(dead_code)]
+    DtlbMisses = 0x0c,
+}
+
+impl HpmEvent {
+    /// The class a retired instruction is counted in, besides
+    /// `Instructions`.
+    pub fn class(inst: u64) -> HpmEvent {
+        match inst & 0x7f {
+            0x03 | 0x07 => HpmEvent::Loads,
+            0x23 | 0x27 => HpmEvent::Stores,
+            0x63 => HpmEvent::Branches,
+            0x67 | 0x6f => HpmEvent::Jumps,
+            0x2f => HpmEvent::AtomicInstructions,
+            // OP and OP-32 with funct7 = MULDIV.
+            0x33 | 0x3b if inst >> 25 == 0b0000001 => HpmEvent::MulDivInstructions,
+            0x0f | 0x73 => HpmEvent::SystemInstructions,
+            _ => HpmEvent::IntegerInstructions,
+        }
+    }
+}
+
+/// Hardware performance monitor of a hart: mcycle, minstret and the
+/// mhpmcounter3-31 event counters.
+pub struct Hpm {
+    // Indexed like the counters: 0 is mcycle, 2 minstret, 3-31 mhpmcounter.
+    counters: [u64; 32],
+    events: [u64; 32],
+    inhibit: u64,
+    // Counters with an event selected.
+    active: u64,
+    // Counters written by the instruction being retired, which do not count
+    // that instruction.
+    written: u64,
+}
+
+impl Hpm {
+    pub fn new() -> Self {
+        Self {
+            counters: [0; 32],
+            events: [0; 32],
+            inhibit: 0,
+            active: 0,
+            written: 0,
+        }
+    }
+
+    /// Read a counter CSR. Returns None for every other CSR.
+    pub fn csr_load(&self, addr: u64) -> Option<u64> {
+        match addr {
+            MCYCLE | MINSTRET | MHPMCOUNTER3..=MHPMCOUNTER31 => {
+                Some(self.counters[(addr - MCYCLE) as usize])
+            }
+            CYCLE | INSTRET | HPMCOUNTER3..=HPMCOUNTER31 => {
+                Some(self.counters[(addr - CYCLE) as usize])
+            }
+            MHPMEVENT3..=MHPMEVENT31 => Some(self.events[(addr - MCOUNTINHIBIT) as usize]),
+            MCOUNTINHIBIT => Some(self.inhibit),
+            // Bit i of scountovf is mhpmevent_i.OF.
+            SCOUNTOVF => Some((3..32).fold(0, |value, i| {
+                value | ((self.events[i] & MHPMEVENT_OF != 0) as u64) << i
+            })),
+            _ => None,
+        }
+    }
+
+    /// Write a counter CSR. Returns false for every other CSR.
+    pub fn csr_store(&mut self, addr: u64, value: u64) -> bool {
+        match addr {
+            MCYCLE | MINSTRET | MHPMCOUNTER3..=MHPMCOUNTER31 => {
+                let i = (addr - MCYCLE) as usize;
+                self.counters[i] = value;
+                self.written |= 1 << i;
+            }
+            MHPMEVENT3..=MHPMEVENT31 => {
+                let i = (addr - MCOUNTINHIBIT) as usize;
+                self.events[i] = value & MHPMEVENT_WRITABLE;
+                self.active &= !(1 << i);
+                if value & MHPMEVENT_EVENT != 0 {
+                    self.active |= 1 << i;
+                }
+            }
+            MCOUNTINHIBIT => self.inhibit = value & MCOUNTINHIBIT_WRITABLE,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Count a retired instruction in `mode`: mcycle and minstret advance
+    /// by one, as do the event counters selecting `Instructions` or the
+    /// class of `inst`. Returns true when a counter overflowed.
+    pub fn retire(&mut self, inst: u64, mode: Mode) -> bool {
+        let counting = !self.inhibit & !std::mem::take(&mut self.written);
+        // There is no cycle model: every instruction takes one cycle.
+        for i in [0, 2] {
+            if counting & (1 << i) != 0 {
+                self.counters[i] = self.counters[i].wrapping_add(1);
+            }
+        }
+        if self.active & counting == 0 {
+            return false;
+        }
+        let class = HpmEvent::class(inst);
+        let mut overflow = false;
+        for i in 3..32 {
+            if counting & self.active & (1 << i) == 0 {
+                continue;
+            }
+            let event = self.events[i] & MHPMEVENT_EVENT;
+            if event == HpmEvent::Instructions as u64 || event == class as u64 {
+                overflow |= self.increment(i, mode);
+            }
+        }
+        overflow
+    }
+
+    /// Count an occurrence of `event` in `mode`. Returns true when a
+    /// counter overflowed.
+    pub fn count(&mut self, event: HpmEvent, mode: Mode) -> bool {
+        if self.active & !self.inhibit == 0 {
+            return false;
+        }
+        let mut overflow = false;
+        for i in 3..32 {
+            if self.active & !self.inhibit & (1 << i) != 0
+                && self.events[i] & MHPMEVENT_EVENT == event as u64
+            {
+                overflow |= self.increment(i, mode);
+            }
+        }
+        overflow
+    }
+
+    // Advance counter `i` unless its event inhibits counting in `mode`.
+    // Returns true when the counter overflows with OF clear, setting OF.
+    fn increment(&mut self, i: usize, mode: Mode) -> bool {
+        let inhibit = match mode {
+            Mode::Machine => MHPMEVENT_MINH,
+            Mode::Supervisor => MHPMEVENT_SINH,
+            Mode::User => MHPMEVENT_UINH,
+        };
+        if self.events[i] & inhibit != 0 {
+            return false;
+        }
+        self.counters[i] = self.counters[i].wrapping_add(1);
+        if self.counters[i] != 0 || self.events[i] & MHPMEVENT_OF != 0 {
+            return false;
+        }
+        self.events[i] |= MHPMEVENT_OF;
+        true
+    }
+}
@@ -0,0 +1,102 @@
+use crate::device::{Device, Pma, WIDTH_32, WIDTH_64};
+use crate::exception::*;
+use crate::power::*;
+use std::io::{self, Write};
+
+pub const HTIF_SIZE: u64 = 0x10;
+
+// Register offsets.
+pub const TOHOST: u64 = 0x0;
+pub const FROMHOST: u64 = 0x8;
+
+// HTIF devices and commands.
+const DEV_SYSCALL: u64 = 0;
+const DEV_CONSOLE: u64 = 1;
+const CMD_PUTCHAR: u64 = 1;
+
+/// The Host-Target Interface of Spike.
+///
+/// Only the exit request of the syscall device and character output on the
+/// console device are supported. Other requests are acknowledged and
+/// dropped.
+pub struct Htif {
+    tohost: u64,
+    fromhost: u64,
+    power: PowerControl,
+}
+
+impl Htif {
+    pub fn new(power: PowerControl) -> Self {
+        Self {
+            tohost: 0,
+            fromhost: 0,
+            power,
+        }
+    }
+
+    fn handle(&mut self, request: u64) {
+        let device = request >> 56;
+        let command = request >> 48 & 0xff;
+        let payload = request & 0xffff_ffff_ffff;
+        match (device, command) {
+            (DEV_SYSCALL, _) if payload & 1 != 0 => {
+                // The 47-bit exit code saturates rather than wrapping to zero.
+                let code = (payload >> 1).min(i32::MAX as u64) as i32;
+                self.power.request(PowerRequest::PowerOff(code));
+            }
+            (DEV_CONSOLE, CMD_PUTCHAR) => {
+                print!("{}", payload as u8 as char);
+                io::stdout()
+                    .flush()
+                    .expect("Failed to flush stdout after writing to HTIF");
+                self.fromhost = (DEV_CONSOLE << 56) | (CMD_PUTCHAR << 48);
+            }
+            _ => {}
+        }
+        self.tohost = 0;
+    }
+}
+
+impl Device for Htif {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        let reg = match offset & !0b111 {
+            TOHOST => self.tohost,
+            FROMHOST => self.fromhost,
+            _ => return Err(Exception::LoadAccessFault(offset)),
+        };
+        match (size, offset & 0b111) {
+            (64, 0) => Ok(reg),
+            (32, 0) => Ok(reg & 0xffff_ffff),
+            (32, 4) => Ok(reg >> 32),
+            _ => Err(Exception::LoadAccessFault(offset)),
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let reg = match offset & !0b111 {
+            TOHOST => &mut self.tohost,
+            FROMHOST => &mut self.fromhost,
+            _ => return Err(Exception::StoreAMOAccessFault(offset)),
+        };
+        *reg = match (size, offset & 0b111) {
+            (64, 0) => value,
+            (32, 0) => (*reg & !0xffff_ffff) | (value & 0xffff_ffff),
+            (32, 4) => (*reg & 0xffff_ffff) | (value << 32),
+            _ => return Err(Exception::StoreAMOAccessFault(offset)),
+        };
+        // A request is complete once the upper half of tohost is written.
+        if offset & !0b111 == TOHOST && (size == 64 || offset & 0b100 != 0) && self.tohost != 0 {
+            self.handle(self.tohost);
+        }
+        Ok(())
+    }
+
+    fn pma(&self) -> Pma {
+        Pma::io(WIDTH_32 | WIDTH_64)
+    }
+
+    fn reset(&mut self) {
+        self.tohost = 0;
+        self.fromhost = 0;
+    }
+}
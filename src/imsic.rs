@@ -0,0 +1,220 @@
+use crate::device::Device;
+use crate::exception::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Number of interrupt identities implemented by each interrupt file.
+/// Identity 0 is never a valid interrupt identity.
+pub const IMSIC_NUM_IDS: u64 = 255;
+
+// Indirectly accessed interrupt-file registers (selected through *iselect).
+pub const EIDELIVERY: u64 = 0x70;
+pub const EITHRESHOLD: u64 = 0x72;
+pub const EIP0: u64 = 0x80;
+pub const EIP63: u64 = 0xbf;
+pub const EIE0: u64 = 0xc0;
+pub const EIE63: u64 = 0xff;
+
+// Memory-mapped registers of an interrupt file page.
+pub const SETEIPNUM_LE: u64 = 0x0;
+pub const SETEIPNUM_BE: u64 = 0x4;
+
+const EI_WORDS: usize = (IMSIC_NUM_IDS as usize + 64) / 64;
+
+/// A single IMSIC interrupt file. Each hart has one for M-mode and one for
+/// S-mode.
+pub struct InterruptFile {
+    eidelivery: u64,
+    eithreshold: u64,
+    eip: [u64; EI_WORDS],
+    eie: [u64; EI_WORDS],
+}
+
+impl InterruptFile {
+    pub fn new() -> Self {
+        Self {
+            eidelivery: 0,
+            eithreshold: 0,
+            eip: [0; EI_WORDS],
+            eie: [0; EI_WORDS],
+        }
+    }
+
+    /// Make interrupt identity `id` pending, as done by an incoming MSI.
+    pub fn set_pending(&mut self, id: u64) {
+        if id == 0 || id > IMSIC_NUM_IDS {
+            return;
+        }
+        self.eip[(id / 64) as usize] |= 1 << (id % 64);
+    }
+
+    fn clear_pending(&mut self, id: u64) {
+        self.eip[(id / 64) as usize] &= !(1 << (id % 64));
+    }
+
+    // On RV64 only the even-numbered eipX/eieX registers exist, and each one
+    // covers 64 interrupt identities.
+    fn word(reg: u64) -> Option<usize> {
+        if !reg.is_multiple_of(2) {
+            return None;
+        }
+        let word = (reg / 2) as usize;
+        if word < EI_WORDS {
+            Some(word)
+        } else {
+            None
+        }
+    }
+
+    // Mask of the identities implemented in word `word` (identity 0 is not).
+    fn word_mask(word: usize) -> u64 {
+        let first = word as u64 * 64;
+        let mut mask = !0u64;
+        if first + 64 > IMSIC_NUM_IDS + 1 {
+            mask = (1 << (IMSIC_NUM_IDS + 1 - first)) - 1;
+        }
+        if word == 0 {
+            mask &= !1;
+        }
+        mask
+    }
+
+    /// Read an indirectly accessed register.
+    pub fn read(&self, isel: u64) -> u64 {
+        match isel {
+            EIDELIVERY => self.eidelivery,
+            EITHRESHOLD => self.eithreshold,
+            EIP0..=EIP63 => match Self::word(isel - EIP0) {
+                Some(word) => self.eip[word],
+                None => 0,
+            },
+            EIE0..=EIE63 => match Self::word(isel - EIE0) {
+                Some(word) => self.eie[word],
+                None => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    /// Write an indirectly accessed register.
+    pub fn write(&mut self, isel: u64, value: u64) {
+        match isel {
+            // Only delivery from the interrupt file itself (1) or off (0) is supported.
+            EIDELIVERY => self.eidelivery = value & 0b1,
+            EITHRESHOLD => self.eithreshold = value & 0x7ff,
+            EIP0..=EIP63 => {
+                if let Some(word) = Self::word(isel - EIP0) {
+                    self.eip[word] = value & Self::word_mask(word);
+                }
+            }
+            EIE0..=EIE63 => {
+                if let Some(word) = Self::word(isel - EIE0) {
+                    self.eie[word] = value & Self::word_mask(word);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The highest priority pending-and-enabled interrupt identity that is
+    /// below the threshold, or 0. Lower identities have higher priority.
+    fn top_id(&self) -> u64 {
+        for (word, (eip, eie)) in self.eip.iter().zip(self.eie.iter()).enumerate() {
+            let active = eip & eie;
+            if active == 0 {
+                continue;
+            }
+            let id = word as u64 * 64 + active.trailing_zeros() as u64;
+            if self.eithreshold != 0 && id >= self.eithreshold {
+                return 0;
+            }
+            return id;
+        }
+        0
+    }
+
+    /// Value of the *topei CSR: the identity in both bits 26:16 and 10:0.
+    pub fn topei(&self) -> u64 {
+        let id = self.top_id();
+        (id << 16) | id
+    }
+
+    /// A write to *topei claims the reported interrupt.
+    pub fn claim(&mut self) {
+        let id = self.top_id();
+        if id != 0 {
+            self.clear_pending(id);
+        }
+    }
+
+    /// Whether the interrupt file signals an external interrupt to the hart.
+    pub fn interrupt_pending(&self) -> bool {
+        self.eidelivery == 1 && self.top_id() != 0
+    }
+}
+
+/// The Incoming MSI Controller of a single hart with an M-level and an
+/// S-level interrupt file.
+pub struct Imsic {
+    pub m: InterruptFile,
+    pub s: InterruptFile,
+}
+
+impl Imsic {
+    pub fn new() -> Self {
+        Self {
+            m: InterruptFile::new(),
+            s: InterruptFile::new(),
+        }
+    }
+
+    pub fn file(&mut self, machine: bool) -> &mut InterruptFile {
+        if machine {
+            &mut self.m
+        } else {
+            &mut self.s
+        }
+    }
+}
+
+/// The memory-mapped page of one interrupt file, as mapped on the bus.
+pub struct ImsicFileMmio {
+    imsic: Rc<RefCell<Imsic>>,
+    machine: bool,
+}
+
+impl ImsicFileMmio {
+    pub fn new(imsic: Rc<RefCell<Imsic>>, machine: bool) -> Self {
+        Self { imsic, machine }
+    }
+}
+
+impl Device for ImsicFileMmio {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        // The seteipnum registers always read as zero.
+        Ok(0)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        let id = match offset {
+            SETEIPNUM_LE => value as u32,
+            SETEIPNUM_BE => (value as u32).swap_bytes(),
+            _ => return Ok(()),
+        };
+        self.imsic
+            .borrow_mut()
+            .file(self.machine)
+            .set_pending(id as u64);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        *self.imsic.borrow_mut().file(self.machine) = InterruptFile::new();
+    }
+}
@@ -24,3 +24,30 @@ impl Interrupt {
         }
     }
 }
+
+impl Interrupt {
+    /// Major interrupts in decreasing default priority order, as defined by
+    /// the Advanced Interrupt Architecture.
+    pub const DEFAULT_PRIORITY: [Interrupt; 7] = [
+        Interrupt::MachineExternalInterrupt,
+        Interrupt::MachineSoftwareInterrupt,
+        Interrupt::MachineTimerInterrupt,
+        Interrupt::SupervisorExternalInterrupt,
+        Interrupt::SupervisorSoftwareInterrupt,
+        Interrupt::SupervisorTimerInterrupt,
+        Interrupt::CounterOverflowInterrupt,
+    ];
+
+    /// The bit of this interrupt in mip/mie.
+    pub fn bit(&self) -> u64 {
+        1 << (self.code() & !INTERRUPT_BIT)
+    }
+
+    /// The highest priority interrupt set in a mip/mie-style bit mask.
+    pub fn highest_priority(pending: u64) -> Option<Interrupt> {
+        Self::DEFAULT_PRIORITY
+            .iter()
+            .find(|interrupt| pending & interrupt.bit() != 0)
+            .copied()
+    }
+}
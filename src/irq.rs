@@ -0,0 +1,46 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// An interrupt controller that accepts interrupt requests from devices.
+///
+/// Devices never touch the controller registers directly. They hold an
+/// `IrqLine` and drive it, and the controller decides (through its
+/// per-source gateway) when a request becomes pending.
+pub trait InterruptSink {
+    /// Drive the level of interrupt source `irq`.
+    fn set_level(&mut self, irq: u64, level: bool);
+    /// Signal a single edge on interrupt source `irq`.
+    fn pulse(&mut self, irq: u64);
+}
+
+/// A single interrupt wire from a device to an interrupt controller.
+pub struct IrqLine {
+    sink: Rc<RefCell<dyn InterruptSink>>,
+    irq: u64,
+}
+
+impl IrqLine {
+    pub fn new(sink: Rc<RefCell<dyn InterruptSink>>, irq: u64) -> Self {
+        Self { sink, irq }
+    }
+
+    /// Assert a level-triggered line.
+    pub fn raise(&self) {
+        self.set(true);
+    }
+
+    /// Deassert a level-triggered line.
+    pub fn lower(&self) {
+        self.set(false);
+    }
+
+    pub fn set(&self, level: bool) {
+        self.sink.borrow_mut().set_level(self.irq, level);
+    }
+
+    /// Send one edge on an edge-triggered line.
+    #[allow(dead_code)]
+    pub fn pulse(&self) {
+        self.sink.borrow_mut().pulse(self.irq);
+    }
+}
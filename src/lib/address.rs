@@ -3,6 +3,15 @@
 pub const CLINT_BASE: u64 = 0x200_0000;
 pub const CLINT_SIZE: u64 = 0xc000;
 
+// ACLINT devices. With the legacy SiFive CLINT layout the MSWI and MTIMER
+// regions are placed back to back at CLINT_BASE.
+pub const ACLINT_MSWI_BASE: u64 = 0x200_0000;
+pub const ACLINT_MSWI_SIZE: u64 = 0x4000;
+pub const ACLINT_MTIMER_BASE: u64 = 0x200_4000;
+pub const ACLINT_MTIMER_SIZE: u64 = 0x8000;
+pub const ACLINT_SSWI_BASE: u64 = 0x2f0_0000;
+pub const ACLINT_SSWI_SIZE: u64 = 0x4000;
+
 pub const PLIC_BASE: u64 = 0xc00_0000;
 // The size of the PLIC memory-mapped region, calculated as:
 // 0x200000: the base region size (2MB) for the PLIC registers
@@ -10,6 +19,22 @@ pub const PLIC_BASE: u64 = 0xc00_0000;
 // This accounts for interrupt contexts for a single core in M and S modes, each having a 4KB area
 pub const PLIC_SIZE: u64 = (0x200000 + (1 * 2) * 0x1000);
 
+// Advanced Interrupt Architecture: the M-level and S-level APLIC domains.
+pub const APLIC_M_BASE: u64 = 0xc00_0000;
+pub const APLIC_S_BASE: u64 = 0xd00_0000;
+// Domain control registers (0x4000) followed by one 32-byte IDC per hart.
+pub const APLIC_SIZE: u64 = 0x8000;
+
+// The M-level and S-level IMSIC interrupt files, one 4KB page per hart.
+pub const IMSIC_M_BASE: u64 = 0x2400_0000;
+pub const IMSIC_S_BASE: u64 = 0x2800_0000;
+pub const IMSIC_SIZE: u64 = 0x1000;
+
+pub const ROM_BASE: u64 = 0x1000;
+
+// The SiFive test finisher, through which the guest powers off or resets.
+pub const SIFIVE_TEST_BASE: u64 = 0x10_0000;
+
 pub const UART_BASE: u64 = 0x1000_0000;
 pub const UART_SIZE: u64 = 0x100;
 
@@ -48,6 +73,36 @@ pub const MSTATUSH: u64 = 0x310;
 /// Upper 32 bits of MEDeleg (RV32 only).
 pub const MEDELEGH: u64 = 0x312;
 
+// Machine Counter/Timers
+/// Machine cycle counter.
+pub const MCYCLE: u64 = 0xB00;
+/// Machine instructions-retired counter.
+pub const MINSTRET: u64 = 0xB02;
+/// Machine performance-monitoring counters.
+pub const MHPMCOUNTER3: u64 = 0xB03;
+pub const MHPMCOUNTER31: u64 = 0xB1F;
+
+// Machine Counter Setup
+/// Machine counter-inhibit register.
+pub const MCOUNTINHIBIT: u64 = 0x320;
+/// Machine performance-monitoring event selectors.
+pub const MHPMEVENT3: u64 = 0x323;
+pub const MHPMEVENT31: u64 = 0x33F;
+
+// Machine Configuration
+/// Machine environment configuration register.
+pub const MENVCFG: u64 = 0x30A;
+
+// Machine Memory Protection
+/// Machine security configuration register (Smepmp).
+pub const MSECCFG: u64 = 0x747;
+/// Physical memory protection configuration (even registers only on RV64).
+pub const PMPCFG0: u64 = 0x3A0;
+pub const PMPCFG15: u64 = 0x3AF;
+/// Physical memory protection address registers.
+pub const PMPADDR0: u64 = 0x3B0;
+pub const PMPADDR63: u64 = 0x3EF;
+
 // Machine Trap Handling
 /// Machine scratch register.
 pub const MSCRATCH: u64 = 0x340;
@@ -64,6 +119,18 @@ pub const MTINST: u64 = 0x34A;
 /// Machine second trap value.
 pub const MTVAL2: u64 = 0x34B;
 
+// Machine-Level Window to Indirectly Accessed Registers
+/// Machine indirect register select.
+pub const MISELECT: u64 = 0x350;
+/// Machine indirect register alias.
+pub const MIREG: u64 = 0x351;
+
+// Machine-Level Interrupts (AIA)
+/// Machine top external interrupt.
+pub const MTOPEI: u64 = 0x35C;
+/// Machine top interrupt.
+pub const MTOPI: u64 = 0xFB0;
+
 // Supervisor Trap Setup
 /// Supervisor status register.
 pub const SSTATUS: u64 = 0x100;
@@ -72,7 +139,7 @@ pub const SIE: u64 = 0x104;
 /// Supervisor trap handler base address.
 pub const STVEC: u64 = 0x105;
 /// Supervisor counter enable.
-pub const SCOUINTEREN: u64 = 0x106;
+pub const SCOUNTEREN: u64 = 0x106;
 
 // Supervisor Configuration
 /// Supervisor environment configuration register.
@@ -96,6 +163,24 @@ pub const SIP: u64 = 0x144;
 /// Supervisor count overflow.
 pub const SCOUNTOVF: u64 = 0xDA0;
 
+// Supervisor-Level Window to Indirectly Accessed Registers
+/// Supervisor indirect register select.
+pub const SISELECT: u64 = 0x150;
+/// Supervisor indirect register alias.
+pub const SIREG: u64 = 0x151;
+
+// Supervisor-Level Interrupts (AIA)
+/// Supervisor top external interrupt.
+pub const STOPEI: u64 = 0x15C;
+/// Supervisor top interrupt.
+pub const STOPI: u64 = 0xDB0;
+
+// Supervisor Timer Compare (Sstc)
+/// Supervisor timer compare.
+pub const STIMECMP: u64 = 0x14D;
+/// Virtual supervisor timer compare (requires the hypervisor extension).
+pub const VSTIMECMP: u64 = 0x24D;
+
 // Supervisor Protection and Translation
 /// Supervisor address translation and protection.
 pub const SATP: u64 = 0x180;
@@ -111,3 +196,6 @@ pub const CYCLE: u64 = 0xC00;
 pub const TIME: u64 = 0xC01;
 /// Instructions-retired counter for RDINSTRET instruction.
 pub const INSTRET: u64 = 0xC02;
+/// Performance-monitoring counters.
+pub const HPMCOUNTER3: u64 = 0xC03;
+pub const HPMCOUNTER31: u64 = 0xC1F;
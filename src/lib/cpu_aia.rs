@@ -0,0 +1,116 @@
+use crate::cpu::{Cpu, Mode};
+use crate::imsic::*;
+use crate::interrupt::*;
+use crate::lib::address::*;
+
+// Indirect register numbers of the major interrupt priority array.
+pub const IPRIO0: u64 = 0x30;
+pub const IPRIO15: u64 = 0x3f;
+
+impl Cpu {
+    /// Read a CSR that belongs to the Advanced Interrupt Architecture.
+    /// Returns None for every other CSR.
+    pub fn aia_csr_load(&self, addr: u64) -> Option<u64> {
+        match addr {
+            MIREG => Some(self.indirect_load(Mode::Machine, self.csr.load(MISELECT))),
+            SIREG => Some(self.indirect_load(Mode::Supervisor, self.csr.load(SISELECT))),
+            MTOPEI => Some(self.imsic_topei(Mode::Machine)),
+            STOPEI => Some(self.imsic_topei(Mode::Supervisor)),
+            MTOPI => Some(self.topi(Mode::Machine)),
+            STOPI => Some(self.topi(Mode::Supervisor)),
+            _ => None,
+        }
+    }
+
+    /// Write a CSR that belongs to the Advanced Interrupt Architecture.
+    /// Returns false for every other CSR.
+    pub fn aia_csr_store(&mut self, addr: u64, value: u64) -> bool {
+        match addr {
+            MIREG => self.indirect_store(Mode::Machine, self.csr.load(MISELECT), value),
+            SIREG => self.indirect_store(Mode::Supervisor, self.csr.load(SISELECT), value),
+            // A write to *topei claims the reported interrupt; the value is ignored.
+            MTOPEI | STOPEI => {
+                if let Some(imsic) = &self.bus.imsic {
+                    let mut imsic = imsic.borrow_mut();
+                    match addr {
+                        MTOPEI => imsic.m.claim(),
+                        _ => imsic.s.claim(),
+                    }
+                }
+            }
+            // *topi are read-only.
+            MTOPI | STOPI => {}
+            _ => return false,
+        }
+        true
+    }
+
+    fn indirect_load(&self, mode: Mode, isel: u64) -> u64 {
+        match isel {
+            // The iprio array is read-only zero: all major interrupts use
+            // their default priority.
+            IPRIO0..=IPRIO15 => 0,
+            EIDELIVERY..=EIE63 => match &self.bus.imsic {
+                Some(imsic) => match mode {
+                    Mode::Machine => imsic.borrow().m.read(isel),
+                    _ => imsic.borrow().s.read(isel),
+                },
+                None => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    fn indirect_store(&mut self, mode: Mode, isel: u64, value: u64) {
+        if let EIDELIVERY..=EIE63 = isel {
+            if let Some(imsic) = &self.bus.imsic {
+                match mode {
+                    Mode::Machine => imsic.borrow_mut().m.write(isel, value),
+                    _ => imsic.borrow_mut().s.write(isel, value),
+                }
+            }
+        }
+    }
+
+    fn imsic_topei(&self, mode: Mode) -> u64 {
+        match &self.bus.imsic {
+            Some(imsic) => match mode {
+                Mode::Machine => imsic.borrow().m.topei(),
+                _ => imsic.borrow().s.topei(),
+            },
+            None => 0,
+        }
+    }
+
+    /// Value of mtopi/stopi: the highest priority pending-and-enabled major
+    /// interrupt at that privilege level, in bits 27:16, and its priority.
+    fn topi(&self, mode: Mode) -> u64 {
+        let pending = match mode {
+            Mode::Machine => self.csr.load(MIP) & self.csr.load(MIE) & !self.csr.load(MIDELEG),
+            _ => self.csr.load(SIP) & self.csr.load(SIE),
+        };
+        match Interrupt::highest_priority(pending) {
+            // With a read-only zero iprio array the simplified IPRIO field
+            // is always reported as 1.
+            Some(interrupt) => ((interrupt.code() & !INTERRUPT_BIT) << 16) | 1,
+            None => 0,
+        }
+    }
+
+    /// External interrupt requests from the AIA interrupt controllers as
+    /// (MEIP, SEIP).
+    pub fn aia_external_interrupts(&self) -> (bool, bool) {
+        let (mut meip, mut seip) = (false, false);
+        if let Some(imsic) = &self.bus.imsic {
+            let imsic = imsic.borrow();
+            meip |= imsic.m.interrupt_pending();
+            seip |= imsic.s.interrupt_pending();
+        }
+        if let Some(aplic) = &self.bus.aplic {
+            let aplic = aplic.borrow();
+            meip |= aplic.interrupt_pending(true);
+            seip |= aplic.interrupt_pending(false);
+        }
+        (meip, seip)
+    }
+}
@@ -0,0 +1,111 @@
+use crate::cpu::Cpu;
+use crate::csr::*;
+use crate::interrupt::*;
+use crate::lib::address::*;
+use crate::scheduler::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest time an idle hart sleeps before it polls the host console again.
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+impl Cpu {
+    /// Account for the retired instruction `inst`.
+    pub fn retire(&mut self, inst: u64) {
+        self.retired += 1;
+        self.hpm_retire(inst);
+        self.bus.clint.retire();
+        self.bus.scheduler.retire();
+    }
+
+    /// Check for an interrupt to take, but only when a scheduled device
+    /// event is due or the interrupt state may have changed.
+    pub fn poll_interrupt(&mut self) -> Option<Interrupt> {
+        if !self.bus.scheduler.due() {
+            return None;
+        }
+        let now = self.bus.clint.time();
+        while let Some(event) = self.bus.scheduler.pop_due(now) {
+            match event {
+                // Devices raise their interrupt lines on new host input.
+                Event::DevicePoll => {
+                    self.bus.tick(now);
+                    self.bus.check_interrupts();
+                }
+                // Timer comparators are evaluated by check_interrupt.
+                Event::MachineTimer | Event::SupervisorTimer => {}
+            }
+        }
+        let interrupt = self.check_interrupt();
+        self.schedule_events(now);
+        interrupt
+    }
+
+    /// Stall the hart in WFI until an interrupt is pending and enabled.
+    ///
+    /// The host thread sleeps until the next timer deadline, waking up
+    /// periodically to look for console input. Global interrupt enables are
+    /// ignored: WFI resumes on any locally enabled pending interrupt, even
+    /// if the interrupt is not taken.
+    pub fn wait_for_interrupt(&mut self, deadline: Option<Instant>) {
+        loop {
+            let now = self.bus.clint.time();
+            self.bus.tick(now);
+            self.bus.check_interrupts();
+            self.update_pending();
+            if self.csr_load(MIP) & self.csr_load(MIE) != 0 {
+                break;
+            }
+            // The hart stays in WFI when the deadline passes.
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return;
+            }
+            self.schedule_events(now);
+            let timer_deadline = [Event::MachineTimer, Event::SupervisorTimer]
+                .iter()
+                .filter_map(|event| self.bus.scheduler.deadline(*event))
+                // A comparator set to all ones means the timer is disabled.
+                .filter(|deadline| *deadline != u64::MAX)
+                .min();
+            let sleep = match timer_deadline {
+                Some(deadline) => self.bus.clint.wait_until(deadline),
+                None => IDLE_POLL_INTERVAL,
+            };
+            if !sleep.is_zero() {
+                thread::sleep(sleep.min(IDLE_POLL_INTERVAL));
+            }
+        }
+        self.wfi = false;
+        self.bus.scheduler.mark_dirty();
+    }
+
+    // Schedule the next deadline of every device and arm the countdown to
+    // the nearest one.
+    fn schedule_events(&mut self, now: u64) {
+        let mtimecmp = self.bus.clint.mtimecmp(0);
+        if mtimecmp > now {
+            self.bus.scheduler.schedule(Event::MachineTimer, mtimecmp);
+        } else {
+            self.bus.scheduler.cancel(Event::MachineTimer);
+        }
+
+        let stimecmp = self.csr_load(STIMECMP);
+        if self.csr_load(MENVCFG) & MENVCFG_STCE != 0 && stimecmp > now {
+            self.bus
+                .scheduler
+                .schedule(Event::SupervisorTimer, stimecmp);
+        } else {
+            self.bus.scheduler.cancel(Event::SupervisorTimer);
+        }
+
+        if !self.bus.scheduler.is_scheduled(Event::DevicePoll) {
+            self.bus
+                .scheduler
+                .schedule(Event::DevicePoll, now.wrapping_add(DEVICE_POLL_INTERVAL));
+        }
+
+        let deadline = self.bus.scheduler.next_deadline().unwrap_or(u64::MAX);
+        let instructions = self.bus.clint.instructions_until(deadline);
+        self.bus.scheduler.rearm(instructions);
+    }
+}
@@ -0,0 +1,27 @@
+use crate::cpu::Cpu;
+use crate::hpm::*;
+use crate::interrupt::*;
+
+impl Cpu {
+    /// Count a retired instruction on the performance counters.
+    pub fn hpm_retire(&mut self, inst: u64) {
+        if self.hpm.retire(inst, self.mode) {
+            self.raise_counter_overflow();
+        }
+    }
+
+    /// Count an occurrence of `event` on the performance counters.
+    pub fn hpm_event(&mut self, event: HpmEvent) {
+        if self.hpm.count(event, self.mode) {
+            self.raise_counter_overflow();
+        }
+    }
+
+    // A counter overflowed with mhpmevent.OF clear: request a local
+    // counter-overflow interrupt. LCOFIP stays set until software clears it.
+    fn raise_counter_overflow(&mut self) {
+        self.csr
+            .set_pending(Interrupt::CounterOverflowInterrupt.bit(), true);
+        self.bus.scheduler.mark_dirty();
+    }
+}
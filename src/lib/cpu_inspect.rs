@@ -16,7 +16,7 @@ impl Cpu {
             let idx4 = i * 4 + 3;
 
             if idx4 < self.regs.len() {
-                println!(
+                eprintln!(
                     "{:<3} | {:<5} | {:<18} |{:<3} | {:<5} | {:<18} |{:<3} | {:<5} | {:<18} |{:<3} | {:<5} | {:<18}",
                     idx1,
                     register_names[idx1],
@@ -50,6 +50,6 @@ impl Cpu {
                 self.csr_load(SCAUSE),
             ),
         );
-        println!("{}", csr_output);
+        eprintln!("{}", csr_output);
     }
 }
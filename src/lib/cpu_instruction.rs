@@ -1,6 +1,7 @@
 #![allow(unused)]
 
 use crate::cpu::Mode;
+use crate::csr::*;
 use crate::lib::address::*;
 use crate::{cpu::Cpu, Exception};
 
@@ -19,70 +20,73 @@ impl Cpu {
     }
 
     #[inline(always)]
-    pub fn execute_jal(&mut self, rd: u64, imm: u64) {
-        self.regs[rd as usize] = self.pc.wrapping_add(4);
-        self.pc = self.pc.wrapping_add(imm);
+    pub fn execute_jal(&mut self, rd: u64, imm: u64) -> Result<(), Exception> {
+        let t = self.pc;
+        self.jump(self.pc.wrapping_add(imm))?;
+        self.regs[rd as usize] = t.wrapping_add(4);
+        Ok(())
     }
 
     #[inline(always)]
-    pub fn execute_jalr(&mut self, rd: u64, rs1: u64, imm: u64) {
+    pub fn execute_jalr(&mut self, rd: u64, rs1: u64, imm: u64) -> Result<(), Exception> {
         let t = self.pc;
-        self.pc = (self.regs[rs1 as usize].wrapping_add(imm)) & !1;
+        self.jump((self.regs[rs1 as usize].wrapping_add(imm)) & !1)?;
         self.regs[rd as usize] = t.wrapping_add(4);
+        Ok(())
     }
 
     #[inline(always)]
-    pub fn execute_beq(&mut self, rs1: u64, rs2: u64, imm: u64) -> u64 {
+    pub fn execute_beq(&mut self, rs1: u64, rs2: u64, imm: u64) -> Result<u64, Exception> {
         if self.regs[rs1 as usize] == self.regs[rs2 as usize] {
-            self.pc = self.pc.wrapping_add(imm);
-            return 0;
+            self.jump(self.pc.wrapping_add(imm))?;
+            return Ok(0);
         }
-        4
+        Ok(4)
     }
 
     #[inline(always)]
-    pub fn execute_bne(&mut self, rs1: u64, rs2: u64, imm: u64) -> u64 {
+    pub fn execute_bne(&mut self, rs1: u64, rs2: u64, imm: u64) -> Result<u64, Exception> {
         if self.regs[rs1 as usize] != self.regs[rs2 as usize] {
-            self.pc = self.pc.wrapping_add(imm);
-            return 0;
+            self.jump(self.pc.wrapping_add(imm))?;
+            return Ok(0);
         }
-        4
+        Ok(4)
     }
 
     #[inline(always)]
-    pub fn execute_blt(&mut self, rs1: u64, rs2: u64, imm: u64) -> u64 {
+    pub fn execute_blt(&mut self, rs1: u64, rs2: u64, imm: u64) -> Result<u64, Exception> {
         if (self.regs[rs1 as usize] as i64) < (self.regs[rs2 as usize] as i64) {
-            self.pc = self.pc.wrapping_add(imm);
-            return 0;
+            self.jump(self.pc.wrapping_add(imm))?;
+            return Ok(0);
         }
-        4
+        Ok(4)
     }
 
     #[inline(always)]
-    pub fn execute_bge(&mut self, rs1: u64, rs2: u64, imm: u64) -> u64 {
+    pub fn execute_bge(&mut self, rs1: u64, rs2: u64, imm: u64) -> Result<u64, Exception> {
         if (self.regs[rs1 as usize] as i64) >= (self.regs[rs2 as usize] as i64) {
-            self.pc = self.pc.wrapping_add(imm);
-            return 0;
+            self.jump(self.pc.wrapping_add(imm))?;
+            return Ok(0);
         }
-        4
+        Ok(4)
     }
 
     #[inline(always)]
-    pub fn execute_bltu(&mut self, rs1: u64, rs2: u64, imm: u64) -> u64 {
+    pub fn execute_bltu(&mut self, rs1: u64, rs2: u64, imm: u64) -> Result<u64, Exception> {
         if self.regs[rs1 as usize] < self.regs[rs2 as usize] {
-            self.pc = self.pc.wrapping_add(imm);
-            return 0;
+            self.jump(self.pc.wrapping_add(imm))?;
+            return Ok(0);
         }
-        4
+        Ok(4)
     }
 
     #[inline(always)]
-    pub fn execute_bgeu(&mut self, rs1: u64, rs2: u64, imm: u64) -> u64 {
+    pub fn execute_bgeu(&mut self, rs1: u64, rs2: u64, imm: u64) -> Result<u64, Exception> {
         if self.regs[rs1 as usize] >= self.regs[rs2 as usize] {
-            self.pc = self.pc.wrapping_add(imm);
-            return 0;
+            self.jump(self.pc.wrapping_add(imm))?;
+            return Ok(0);
         }
-        4
+        Ok(4)
     }
 
     #[inline(always)]
@@ -272,14 +276,36 @@ impl Cpu {
     #[inline(always)]
     pub fn execute_fence_tso(&mut self) {}
 
-    #[inline(always)]
-    pub fn execute_sfence_vma(&mut self) {
-        return;
+    pub fn execute_sfence_vma(&mut self, inst: u64) -> Result<(), Exception> {
+        // SFENCE.VMA is illegal in U-mode, and in S-mode when TVM=1.
+        let tvm = self.csr_load(MSTATUS) & MSTATUS_TVM != 0;
+        match self.mode {
+            Mode::User => Err(Exception::IllegalInstruction(inst)),
+            Mode::Supervisor if tvm => Err(Exception::IllegalInstruction(inst)),
+            _ => Ok(()),
+        }
     }
 
     #[inline(always)]
     pub fn execute_pause(&mut self) {}
 
+    pub fn execute_wfi(&mut self, inst: u64) -> Result<(), Exception> {
+        // When TW=1, if WFI is executed in any less-privileged mode and it
+        // does not complete within an implementation-specific, bounded time
+        // limit, the WFI instruction causes an illegal-instruction exception.
+        // When S-mode is implemented, executing WFI in U-mode causes an
+        // illegal-instruction exception under the same condition. The time
+        // limit is always 0 here.
+        let tw = self.csr_load(MSTATUS) & MSTATUS_TW != 0;
+        match self.mode {
+            Mode::User => return Err(Exception::IllegalInstruction(inst)),
+            Mode::Supervisor if tw => return Err(Exception::IllegalInstruction(inst)),
+            _ => {}
+        }
+        self.wfi = true;
+        Ok(())
+    }
+
     #[inline(always)]
     pub fn execute_ecall(&mut self) -> Result<(), Exception> {
         match self.mode {
@@ -361,14 +387,18 @@ impl Cpu {
     #[inline(always)]
     pub fn execute_csrrs(&mut self, csr_addr: u64, rd: u64, rs1: u64) {
         let t = self.csr_load(csr_addr);
-        self.csr_store(csr_addr, t | self.regs[rs1 as usize]);
+        if rs1 != 0 {
+            self.csr_store(csr_addr, t | self.regs[rs1 as usize]);
+        }
         self.regs[rd as usize] = if rd == 0 { self.regs[rd as usize] } else { t };
     }
 
     #[inline(always)]
     pub fn execute_csrrc(&mut self, csr_addr: u64, rd: u64, rs1: u64) {
         let t = self.csr_load(csr_addr);
-        self.csr_store(csr_addr, t & (!self.regs[rs1 as usize]));
+        if rs1 != 0 {
+            self.csr_store(csr_addr, t & (!self.regs[rs1 as usize]));
+        }
         self.regs[rd as usize] = if rd == 0 { self.regs[rd as usize] } else { t };
     }
 
@@ -390,14 +420,18 @@ impl Cpu {
     #[inline(always)]
     pub fn execute_csrrsi(&mut self, csr_addr: u64, rd: u64, uimm: u64) {
         let t = self.csr_load(csr_addr);
-        self.csr_store(csr_addr, t | uimm);
+        if uimm != 0 {
+            self.csr_store(csr_addr, t | uimm);
+        }
         self.regs[rd as usize] = if rd == 0 { self.regs[rd as usize] } else { t };
     }
 
     #[inline(always)]
     pub fn execute_csrrci(&mut self, csr_addr: u64, rd: u64, uimm: u64) {
         let t = self.csr_load(csr_addr);
-        self.csr_store(csr_addr, t & (!uimm));
+        if uimm != 0 {
+            self.csr_store(csr_addr, t & (!uimm));
+        }
         self.regs[rd as usize] = if rd == 0 { self.regs[rd as usize] } else { t };
     }
 
@@ -554,7 +588,16 @@ impl Cpu {
     #[inline(always)]
     pub fn execute_amomaxu_d(&mut self) {}
 
-    pub fn execute_sret(&mut self) {
+    pub fn execute_sret(&mut self, inst: u64) -> Result<(), Exception> {
+        // SRET is illegal in U-mode. When TSR=1, attempts to execute SRET
+        // while executing in S-mode will raise an illegal-instruction
+        // exception.
+        let tsr = self.csr_load(MSTATUS) & MSTATUS_TSR != 0;
+        match self.mode {
+            Mode::User => return Err(Exception::IllegalInstruction(inst)),
+            Mode::Supervisor if tsr => return Err(Exception::IllegalInstruction(inst)),
+            _ => {}
+        }
         // An MRET or SRET instruction is used to return from a
         // trap in M-mode or S-mode respectively. When
         // executing an xRET instruction, supposing xPP holds
@@ -588,9 +631,13 @@ impl Cpu {
         self.csr_store(SSTATUS, sstatus);
         // update program counter
         self.pc = self.csr_load(SEPC);
+        Ok(())
     }
 
-    pub fn execute_mret(&mut self) {
+    pub fn execute_mret(&mut self, inst: u64) -> Result<(), Exception> {
+        if self.mode != Mode::Machine {
+            return Err(Exception::IllegalInstruction(inst));
+        }
         // An MRET or SRET instruction is used to return from a
         // trap in M-mode or S-mode respectively. When
         // executing an xRET instruction, supposing xPP holds
@@ -617,10 +664,14 @@ impl Cpu {
         };
         // set MPIE to 1
         mstatus |= 1 << 7;
-        // set MPP to least-privileged supported mode (U: 0b00)
+        // set MPP to least-privileged supported mode (U: 0b00, else M: 0b11)
         mstatus &= !(0b11 << 11);
+        if !self.has_extension('u') {
+            mstatus |= Mode::Machine.code() << 11;
+        }
         self.csr_store(MSTATUS, mstatus);
         // update program counter
         self.pc = self.csr_load(MEPC);
+        Ok(())
     }
 }
@@ -0,0 +1,84 @@
+use crate::cpu::Cpu;
+use crate::exception::*;
+use crate::lib::address::*;
+use crate::machine::MisalignedPolicy;
+use crate::pmp::*;
+
+// Misaligned accesses crossing a page boundary trap under the
+// trap-on-boundary policy.
+const PAGE_SIZE: u64 = 4096;
+
+impl Cpu {
+    /// Jump to `target`. Targets that are not IALIGN-aligned raise an
+    /// instruction-address-misaligned exception and leave the pc unchanged.
+    pub fn jump(&mut self, target: u64) -> Result<(), Exception> {
+        // IALIGN is 16 bits with the C extension and 32 bits without it.
+        let ialign = if self.csr.load(MISA) & (1 << 2) != 0 {
+            2
+        } else {
+            4
+        };
+        if !target.is_multiple_of(ialign) {
+            return Err(Exception::InstructionAddressMisaligned(target));
+        }
+        self.pc = target;
+        Ok(())
+    }
+
+    // Whether an access of `size` bits at `addr` crosses a page or a device
+    // boundary.
+    fn crosses_boundary(&mut self, addr: u64, size: u64) -> bool {
+        let last = addr.wrapping_add(size / 8 - 1);
+        addr / PAGE_SIZE != last / PAGE_SIZE || self.bus.crosses_region(addr, size)
+    }
+
+    /// Load a value that is not naturally aligned.
+    pub fn load_misaligned(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let crosses = self.crosses_boundary(addr, size);
+        match self.misaligned {
+            MisalignedPolicy::Trap => Err(Exception::LoadAddressMisaligned(addr)),
+            MisalignedPolicy::TrapOnBoundary if crosses => {
+                Err(Exception::LoadAddressMisaligned(addr))
+            }
+            _ if !crosses => {
+                self.pmp_check(addr, size, Access::Load)?;
+                self.bus.load(addr, size)
+            }
+            // Accesses spanning two pages or devices are split into bytes.
+            _ => {
+                let mut value = 0;
+                for i in 0..size / 8 {
+                    let byte_addr = addr.wrapping_add(i);
+                    self.pmp_check(byte_addr, 8, Access::Load)?;
+                    value |= self.bus.load(byte_addr, 8)? << (i * 8);
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    /// Store a value that is not naturally aligned.
+    pub fn store_misaligned(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let crosses = self.crosses_boundary(addr, size);
+        match self.misaligned {
+            MisalignedPolicy::Trap => Err(Exception::StoreAMOAddressMisaligned(addr)),
+            MisalignedPolicy::TrapOnBoundary if crosses => {
+                Err(Exception::StoreAMOAddressMisaligned(addr))
+            }
+            _ if !crosses => {
+                self.pmp_check(addr, size, Access::Store)?;
+                self.bus.store(addr, size, value)
+            }
+            // Every byte is checked before the first one is written.
+            _ => {
+                for i in 0..size / 8 {
+                    self.pmp_check(addr.wrapping_add(i), 8, Access::Store)?;
+                }
+                for i in 0..size / 8 {
+                    self.bus.store(addr.wrapping_add(i), 8, value >> (i * 8))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use crate::cpu::{Cpu, Mode};
+use crate::device::HostPage;
+use crate::pmp::*;
+use std::ptr;
+
+/// A RAM page the hart accesses directly, bypassing the bus.
+#[derive(Debug, Copy, Clone)]
+pub struct CachedPage {
+    pub page: HostPage,
+    /// The privilege mode the PMP allowed the whole page for.
+    pub mode: Mode,
+}
+
+// Slot of the page cache used for each kind of access.
+fn slot(access: Access) -> usize {
+    match access {
+        Access::Fetch => 0,
+        Access::Load => 1,
+        Access::Store => 2,
+    }
+}
+
+impl Cpu {
+    // Host address of an access of `size` bits at `addr`, if it can bypass
+    // the bus. The cached page is replaced when the access misses it.
+    fn cached_ptr(&mut self, addr: u64, size: u64, access: Access) -> Option<*mut u8> {
+        let mode = self.effective_mode(access);
+        let slot = slot(access);
+        let hit = |cached: &CachedPage| cached.mode == mode && cached.page.contains(addr, size);
+        if !self.page_cache[slot].as_ref().is_some_and(hit) {
+            let page = self.bus.host_page(addr, access)?;
+            // Only pages the PMP allows as a whole are cached, so that every
+            // access to them passes without being checked.
+            self.pmp.check(page.base, page.len * 8, access, mode).ok()?;
+            let cached = CachedPage { page, mode };
+            if !hit(&cached) {
+                return None;
+            }
+            self.page_cache[slot] = Some(cached);
+        }
+        let page = self.page_cache[slot]?.page;
+        // SAFETY: the access lies within the page, whose host memory lives
+        // as long as the bus.
+        Some(unsafe { page.ptr.add((addr - page.base) as usize) })
+    }
+
+    /// Read a naturally aligned value from RAM without going through the
+    /// bus. Returns None if the access has to take the slow path.
+    #[inline(always)]
+    pub fn cached_read(&mut self, addr: u64, size: u64, access: Access) -> Option<u64> {
+        let ptr = self.cached_ptr(addr, size, access)?;
+        // SAFETY: see cached_ptr.
+        unsafe {
+            Some(match size {
+                8 => *ptr as u64,
+                16 => u16::from_le(ptr::read_unaligned(ptr as *const u16)) as u64,
+                32 => u32::from_le(ptr::read_unaligned(ptr as *const u32)) as u64,
+                _ => u64::from_le(ptr::read_unaligned(ptr as *const u64)),
+            })
+        }
+    }
+
+    /// Write a naturally aligned value to RAM without going through the bus.
+    /// Returns false if the access has to take the slow path.
+    #[inline(always)]
+    pub fn cached_write(&mut self, addr: u64, size: u64, value: u64) -> bool {
+        let ptr = match self.cached_ptr(addr, size, Access::Store) {
+            Some(ptr) => ptr,
+            None => return false,
+        };
+        // SAFETY: see cached_ptr.
+        unsafe {
+            match size {
+                8 => *ptr = value as u8,
+                16 => ptr::write_unaligned(ptr as *mut u16, (value as u16).to_le()),
+                32 => ptr::write_unaligned(ptr as *mut u32, (value as u32).to_le()),
+                _ => ptr::write_unaligned(ptr as *mut u64, value.to_le()),
+            }
+        }
+        true
+    }
+
+    /// Forget the cached pages, after the PMP configuration changed.
+    pub fn flush_page_cache(&mut self) {
+        self.page_cache = [None; 3];
+    }
+}
@@ -0,0 +1,30 @@
+use crate::cpu::{Cpu, Mode};
+use crate::csr::*;
+use crate::exception::*;
+use crate::lib::address::*;
+use crate::pmp::*;
+
+impl Cpu {
+    /// The privilege mode an access is checked with. In M-mode with MPRV
+    /// set, loads and stores use the privilege mode held in MPP.
+    pub fn effective_mode(&self, access: Access) -> Mode {
+        if access == Access::Fetch || self.mode != Mode::Machine {
+            return self.mode;
+        }
+        let mstatus = self.csr.load(MSTATUS);
+        if mstatus & MSTATUS_MPRV == 0 {
+            return self.mode;
+        }
+        match (mstatus >> 11) & 0b11 {
+            0b11 => Mode::Machine,
+            0b01 => Mode::Supervisor,
+            _ => Mode::User,
+        }
+    }
+
+    /// Check a physical memory access against the PMP entries.
+    pub fn pmp_check(&self, addr: u64, size: u64, access: Access) -> Result<(), Exception> {
+        self.pmp
+            .check(addr, size, access, self.effective_mode(access))
+    }
+}
@@ -0,0 +1,62 @@
+use crate::cpu::{Cpu, Mode};
+use crate::csr::*;
+use crate::exception::*;
+use crate::lib::address::*;
+
+// The unprivileged counters cycle, time, instret and hpmcounter3-31.
+const COUNTERS: std::ops::RangeInclusive<u64> = CYCLE..=HPMCOUNTER31;
+
+impl Cpu {
+    /// Whether misa reports the extension `letter`, such as 's' or 'u'.
+    pub fn has_extension(&self, letter: char) -> bool {
+        self.csr.load(MISA) & (1 << (letter as u64 - 'a' as u64)) != 0
+    }
+
+    /// Check that the current privilege mode may access the CSR `csr`,
+    /// writing it when `write` is set. `inst` is the CSR instruction,
+    /// reported by the illegal-instruction exception otherwise.
+    pub fn check_csr_access(&self, csr: u64, write: bool, inst: u64) -> Result<(), Exception> {
+        let illegal = Err(Exception::IllegalInstruction(inst));
+        // csr[11:10] is 0b11 for read-only CSRs and csr[9:8] is the lowest
+        // privilege level that can access the CSR. Level 0b10 belongs to the
+        // hypervisor extension, which is not implemented.
+        let lowest = (csr >> 8) & 0b11;
+        if lowest == 0b10 || self.mode.code() < lowest {
+            return illegal;
+        }
+        if write && (csr >> 10) & 0b11 == 0b11 {
+            return illegal;
+        }
+        // When TVM=1, attempts to read or write the satp CSR while executing
+        // in S-mode will raise an illegal-instruction exception.
+        if csr == SATP && self.mode == Mode::Supervisor && self.csr_load(MSTATUS) & MSTATUS_TVM != 0
+        {
+            return illegal;
+        }
+        // Sstc: stimecmp can only be accessed below M-mode when
+        // menvcfg.STCE and mcounteren.TM are both set.
+        if csr == STIMECMP
+            && self.mode != Mode::Machine
+            && (self.csr_load(MENVCFG) & MENVCFG_STCE == 0
+                || self.csr_load(MCOUNTEREN) & (1 << (TIME - CYCLE)) == 0)
+        {
+            return illegal;
+        }
+        // When a bit of mcounteren is clear, reading the matching counter
+        // below M-mode is illegal. scounteren does the same for U-mode when
+        // S-mode is implemented.
+        if COUNTERS.contains(&csr) {
+            let bit = 1 << (csr - CYCLE);
+            if self.mode != Mode::Machine && self.csr_load(MCOUNTEREN) & bit == 0 {
+                return illegal;
+            }
+            if self.mode == Mode::User
+                && self.has_extension('s')
+                && self.csr_load(SCOUNTEREN) & bit == 0
+            {
+                return illegal;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use crate::cpu::{Cpu, Mode};
+use crate::exception::*;
+use crate::lib::address::*;
+use crate::power::PowerRequest;
+use crate::run::*;
+use std::time::Instant;
+
+// The time limit is checked once every this many steps.
+const TIMEOUT_CHECK_INTERVAL: u64 = 0x1000;
+
+impl Cpu {
+    /// Whether the emulator stops at `exception` instead of trapping.
+    /// `in_trap_entry` tells that no instruction has completed since the
+    /// last trap was taken.
+    ///
+    /// Besides the exceptions selected by the halt policy, this always stops
+    /// at a trap handler that faults on its first instruction when the trap
+    /// would enter the same handler again: the guest could never leave that
+    /// loop, and it retires no instructions for the limits to catch.
+    pub fn halts_on(&self, exception: Exception, in_trap_entry: bool) -> bool {
+        self.halt.causes & (1 << exception.code()) != 0
+            || (self.halt.machine_traps && self.exception_mode(exception) == Mode::Machine)
+            || (in_trap_entry && (self.halt.double_fault || self.retraps(exception)))
+    }
+
+    // Whether taking `exception` jumps back to the current pc. Exceptions
+    // always enter the base address of the trap vector.
+    fn retraps(&self, exception: Exception) -> bool {
+        let tvec = match self.exception_mode(exception) {
+            Mode::Machine => MTVEC,
+            _ => STVEC,
+        };
+        self.csr_load(tvec) & !0b11 == self.pc
+    }
+
+    /// Run the machine until the guest powers it off, the halt policy stops
+    /// at an exception or a limit is reached.
+    pub fn run(&mut self, limits: &RunLimits) -> RunOutcome {
+        let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+        let mut steps: u64 = 0;
+        let mut in_trap_entry = false;
+        loop {
+            if limits
+                .max_instructions
+                .is_some_and(|max| self.retired >= max)
+            {
+                return RunOutcome::InstructionLimit;
+            }
+            steps += 1;
+            if steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL)
+                && deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return RunOutcome::Timeout;
+            }
+
+            let pc = self.pc;
+            match self
+                .fetch()
+                .and_then(|inst| self.execute(inst).map(|_| inst))
+            {
+                Ok(inst) => {
+                    self.retire(inst);
+                    in_trap_entry = false;
+                }
+                Err(e) => {
+                    if self.halts_on(e, in_trap_entry) {
+                        return RunOutcome::Fault { exception: e, pc };
+                    }
+                    self.handle_exception(e);
+                    in_trap_entry = true;
+                }
+            }
+            match self.bus.power.take() {
+                Some(PowerRequest::PowerOff(code)) => return RunOutcome::PowerOff { code },
+                Some(PowerRequest::Reset) => {
+                    self.reset();
+                    in_trap_entry = false;
+                    continue;
+                }
+                None => (),
+            }
+            if self.wfi {
+                self.wait_for_interrupt(deadline);
+                if self.wfi {
+                    return RunOutcome::Timeout;
+                }
+            }
+            if let Some(interrupt) = self.poll_interrupt() {
+                self.handle_interrupt(interrupt);
+                in_trap_entry = true;
+            }
+        }
+    }
+}
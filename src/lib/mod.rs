@@ -1,3 +1,11 @@
 pub mod address;
+pub mod cpu_aia;
+pub mod cpu_events;
+pub mod cpu_hpm;
 pub mod cpu_inspect;
 pub mod cpu_instruction;
+pub mod cpu_misaligned;
+pub mod cpu_page_cache;
+pub mod cpu_pmp;
+pub mod cpu_privilege;
+pub mod cpu_run;
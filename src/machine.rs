@@ -0,0 +1,571 @@
+use crate::clint::{ClintLayout, ClockMode};
+use crate::exception::Exception;
+use crate::htif::HTIF_SIZE;
+use crate::lib::address::*;
+use crate::plic::Trigger;
+use crate::profile;
+use crate::rom::ROM_SIZE;
+use crate::sifive_test::SIFIVE_TEST_SIZE;
+use crate::sifive_uart::SIFIVE_UART_SIZE;
+use crate::uart::UART_IRQ;
+use crate::virtio::VIRTIO_MMIO_SIZE;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+
+/// The single-letter ISA extensions the hart implements. misa must not
+/// advertise others: C, for instance, would change IALIGN without
+/// compressed instructions being decoded.
+pub const IMPLEMENTED_EXTENSIONS: &str = "imasu";
+
+/// The interrupt controller wired to the external interrupt inputs of the hart.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IrqChip {
+    /// SiFive-compatible Platform-Level Interrupt Controller.
+    Plic,
+    /// Advanced Interrupt Architecture: an APLIC in front of a per-hart IMSIC.
+    Aia,
+}
+
+impl IrqChip {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "plic" => Some(IrqChip::Plic),
+            "aia" => Some(IrqChip::Aia),
+            _ => None,
+        }
+    }
+}
+
+/// How the hart handles loads and stores that are not naturally aligned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MisalignedPolicy {
+    /// Misaligned accesses are carried out transparently, even across page
+    /// and device boundaries.
+    Hardware,
+    /// Every misaligned access raises an address-misaligned exception.
+    Trap,
+    /// Misaligned accesses within a page and a single device are carried
+    /// out, the others raise an address-misaligned exception.
+    TrapOnBoundary,
+}
+
+impl MisalignedPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hardware" => Some(MisalignedPolicy::Hardware),
+            "trap" => Some(MisalignedPolicy::Trap),
+            "trap-on-boundary" => Some(MisalignedPolicy::TrapOnBoundary),
+            _ => None,
+        }
+    }
+}
+
+/// Exceptions that stop the emulator instead of trapping into the guest.
+/// By default every exception traps, unless a trap handler faults on its
+/// first instruction and would only be entered again.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct HaltPolicy {
+    /// Bit n set: halt on exceptions with cause n.
+    pub causes: u64,
+    /// Halt on any exception taken into M-mode.
+    pub machine_traps: bool,
+    /// Halt when a trap handler faults before its first instruction
+    /// completes, such as when the trap vector is not mapped.
+    pub double_fault: bool,
+}
+
+impl HaltPolicy {
+    /// Parse a list of exception names, "m-mode" and "double-fault".
+    pub fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        let mut policy = Self::default();
+        for name in names {
+            match name {
+                "m-mode" => policy.machine_traps = true,
+                "double-fault" => policy.double_fault = true,
+                _ => match Exception::code_from_name(name) {
+                    Some(code) => policy.causes |= 1 << code,
+                    None => return Err(format!("unknown exception: {}", name)),
+                },
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// The kinds of device a machine description can place on the bus.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceKind {
+    Uart,
+    Plic,
+    AplicM,
+    AplicS,
+    ImsicM,
+    ImsicS,
+    /// Legacy SiFive CLINT: an MSWI followed by an MTIMER.
+    Clint,
+    AclintMswi,
+    AclintMtimer,
+    AclintSswi,
+    /// Boot ROM with a reset stub that jumps to the start of memory.
+    Rom,
+    /// SiFive test finisher.
+    SifiveTest,
+    /// Empty virtio-mmio transport slot.
+    VirtioMmio,
+    /// Spike Host-Target Interface (tohost/fromhost).
+    Htif,
+    SifiveUart,
+}
+
+impl DeviceKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceKind::Uart => "uart",
+            DeviceKind::Plic => "plic",
+            DeviceKind::AplicM => "aplic-m",
+            DeviceKind::AplicS => "aplic-s",
+            DeviceKind::ImsicM => "imsic-m",
+            DeviceKind::ImsicS => "imsic-s",
+            DeviceKind::Clint => "clint",
+            DeviceKind::AclintMswi => "aclint-mswi",
+            DeviceKind::AclintMtimer => "aclint-mtimer",
+            DeviceKind::AclintSswi => "aclint-sswi",
+            DeviceKind::Rom => "rom",
+            DeviceKind::SifiveTest => "sifive-test",
+            DeviceKind::VirtioMmio => "virtio-mmio",
+            DeviceKind::Htif => "htif",
+            DeviceKind::SifiveUart => "sifive-uart",
+        }
+    }
+
+    /// Size of the register region of the device.
+    pub fn size(self) -> u64 {
+        match self {
+            DeviceKind::Uart => UART_SIZE,
+            DeviceKind::Plic => PLIC_SIZE,
+            DeviceKind::AplicM | DeviceKind::AplicS => APLIC_SIZE,
+            DeviceKind::ImsicM | DeviceKind::ImsicS => IMSIC_SIZE,
+            DeviceKind::Clint => CLINT_SIZE,
+            DeviceKind::AclintMswi => ACLINT_MSWI_SIZE,
+            DeviceKind::AclintMtimer => ACLINT_MTIMER_SIZE,
+            DeviceKind::AclintSswi => ACLINT_SSWI_SIZE,
+            DeviceKind::Rom => ROM_SIZE,
+            DeviceKind::SifiveTest => SIFIVE_TEST_SIZE,
+            DeviceKind::VirtioMmio => VIRTIO_MMIO_SIZE,
+            DeviceKind::Htif => HTIF_SIZE,
+            DeviceKind::SifiveUart => SIFIVE_UART_SIZE,
+        }
+    }
+}
+
+/// How a host file backing a memory region is mapped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileMapping {
+    /// Guest writes are private to the emulator (copy-on-write).
+    #[default]
+    Private,
+    /// Guest writes go to the file and are visible to other processes.
+    Shared,
+    /// The region is ROM: guest writes fault.
+    ReadOnly,
+}
+
+/// A RAM region, optionally backed by a host file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryConfig {
+    pub base: u64,
+    pub size: u64,
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default)]
+    pub mapping: FileMapping,
+}
+
+impl MemoryConfig {
+    /// Anonymous RAM.
+    pub fn new(base: u64, size: u64) -> Self {
+        Self {
+            base,
+            size,
+            file: None,
+            mapping: FileMapping::Private,
+        }
+    }
+}
+
+/// Parse a memory size: a byte count with an optional K, M or G suffix.
+pub fn parse_size(text: &str) -> Option<u64> {
+    let (digits, shift) = match text.chars().last()? {
+        'K' | 'k' => (&text[..text.len() - 1], 10),
+        'M' | 'm' => (&text[..text.len() - 1], 20),
+        'G' | 'g' => (&text[..text.len() - 1], 30),
+        _ => (text, 0),
+    };
+    let value: u64 = digits.parse().ok()?;
+    value.checked_mul(1 << shift)
+}
+
+/// A device placed on the bus.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    #[serde(rename = "type")]
+    pub kind: DeviceKind,
+    pub base: u64,
+    /// Interrupt source number of devices with an interrupt line.
+    pub irq: Option<u64>,
+    /// Trigger of the PLIC gateway of the interrupt line. With an APLIC the
+    /// guest selects the source mode in sourcecfg instead.
+    #[serde(default)]
+    pub trigger: Trigger,
+}
+
+impl DeviceConfig {
+    pub fn new(kind: DeviceKind, base: u64, irq: Option<u64>) -> Self {
+        Self {
+            kind,
+            base,
+            irq,
+            trigger: Trigger::Level,
+        }
+    }
+}
+
+/// Options describing the machine the emulator builds.
+pub struct MachineConfig {
+    /// Frequency of mtime (the timebase), in Hz.
+    pub timer_freq: u64,
+    pub harts: u64,
+    /// ISA string, such as "rv64imasu".
+    pub isa: String,
+    pub clock: ClockMode,
+    pub misaligned: MisalignedPolicy,
+    pub halt: HaltPolicy,
+    /// Flattened device tree passed to the firmware in a1 by the boot ROM.
+    pub dtb: Option<String>,
+    /// RAM regions. The binary is loaded at the start of the first one.
+    pub memory: Vec<MemoryConfig>,
+    pub devices: Vec<DeviceConfig>,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        let mut config = Self {
+            timer_freq: 650000,
+            harts: 1,
+            isa: "rv64imasu".to_string(),
+            // Virtual time keeps runs reproducible; wall-clock time is opt-in.
+            clock: ClockMode::Virtual {
+                ticks_per_instruction: 1,
+            },
+            misaligned: MisalignedPolicy::Hardware,
+            halt: HaltPolicy::default(),
+            dtb: None,
+            memory: vec![MemoryConfig::new(DRAM_BASE, DRAM_SIZE)],
+            devices: vec![
+                DeviceConfig::new(DeviceKind::Rom, ROM_BASE, None),
+                DeviceConfig::new(DeviceKind::SifiveTest, SIFIVE_TEST_BASE, None),
+                DeviceConfig::new(DeviceKind::Uart, UART_BASE, Some(UART_IRQ)),
+            ],
+        };
+        config.set_irqchip(IrqChip::Plic);
+        config.set_clint_layout(ClintLayout::Sifive);
+        config
+    }
+}
+
+/// A machine description file could not be used.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Parse(e) => write!(f, "{}", e),
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// The layout of a machine description file.
+//
+//     profile = "virt"           # start from a built-in machine
+//     isa = "rv64imasu"
+//     harts = 1
+//     timebase = 10000000
+//     clock = "virtual"          # or "wall"
+//     ticks-per-insn = 1
+//     misaligned = "hardware"    # or "trap", "trap-on-boundary"
+//     dtb = "machine.dtb"        # device tree for the firmware
+//     halt-on = ["double-fault"] # exceptions that stop the emulator
+//
+//     [[memory]]
+//     base = 0x8000_0000
+//     size = 0x800_0000
+//
+//     [[memory]]                 # memory mapped from a host file
+//     base = 0x9000_0000
+//     size = 0x10_0000
+//     file = "results.bin"
+//     mapping = "shared"         # or "private", "read-only"
+//
+//     [[device]]
+//     type = "uart"
+//     base = 0x1000_0000
+//     irq = 10
+//     trigger = "level"          # or "edge"
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct MachineFile {
+    profile: Option<String>,
+    isa: Option<String>,
+    harts: Option<u64>,
+    timebase: Option<u64>,
+    clock: Option<String>,
+    ticks_per_insn: Option<u64>,
+    misaligned: Option<String>,
+    dtb: Option<String>,
+    halt_on: Option<Vec<String>>,
+    #[serde(default)]
+    memory: Vec<MemoryConfig>,
+    #[serde(default, rename = "device")]
+    devices: Vec<DeviceConfig>,
+}
+
+impl MachineConfig {
+    /// Read a machine description file. Settings missing from the file keep
+    /// their default values.
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Self::from_toml(&text)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        let file: MachineFile = toml::from_str(text).map_err(ConfigError::Parse)?;
+        let mut config = match file.profile.as_deref() {
+            Some(name) => profile::profile(name)
+                .ok_or_else(|| ConfigError::Invalid(format!("unknown profile: {}", name)))?,
+            None => Self::default(),
+        };
+        if let Some(isa) = file.isa {
+            config.isa = isa;
+        }
+        if let Some(harts) = file.harts {
+            config.harts = harts;
+        }
+        if let Some(timebase) = file.timebase {
+            config.timer_freq = timebase;
+        }
+        let ticks_per_instruction = file.ticks_per_insn.unwrap_or(1);
+        config.clock = match file.clock.as_deref() {
+            Some("wall") => ClockMode::WallClock,
+            Some("virtual") => ClockMode::Virtual {
+                ticks_per_instruction,
+            },
+            None if file.ticks_per_insn.is_some() => ClockMode::Virtual {
+                ticks_per_instruction,
+            },
+            None => config.clock,
+            Some(clock) => {
+                return Err(ConfigError::Invalid(format!(
+                    "unknown clock mode: {}",
+                    clock
+                )))
+            }
+        };
+        if let Some(name) = file.misaligned {
+            config.misaligned = MisalignedPolicy::from_name(&name).ok_or_else(|| {
+                ConfigError::Invalid(format!("unknown misaligned access policy: {}", name))
+            })?;
+        }
+        if let Some(names) = file.halt_on {
+            config.halt = HaltPolicy::from_names(names.iter().map(String::as_str))
+                .map_err(ConfigError::Invalid)?;
+        }
+        if file.dtb.is_some() {
+            config.dtb = file.dtb;
+        }
+        if !file.memory.is_empty() {
+            config.memory = file.memory;
+        }
+        if !file.devices.is_empty() {
+            config.devices = file.devices;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the described machine can be built.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::Invalid(reason.to_string()));
+        if self.harts != 1 {
+            return invalid("only single-hart machines are supported");
+        }
+        if self.timer_freq == 0 {
+            return invalid("the timebase must be greater than zero");
+        }
+        // Virtual time would otherwise only advance while the hart waits.
+        if let ClockMode::Virtual {
+            ticks_per_instruction: 0,
+        } = self.clock
+        {
+            return invalid("ticks-per-insn must be greater than zero");
+        }
+        self.misa()?;
+        if self.memory.is_empty() {
+            return invalid("at least one memory region is required");
+        }
+        // The binary is loaded into the first region.
+        if self.memory[0].file.is_some() {
+            return invalid("the first memory region cannot be backed by a file");
+        }
+        let count = |kind| {
+            self.devices
+                .iter()
+                .filter(|device| device.kind == kind)
+                .count()
+        };
+        let has = |kind| count(kind) > 0;
+        if has(DeviceKind::Plic) == has(DeviceKind::AplicM) {
+            return invalid("exactly one of a PLIC or an APLIC is required");
+        }
+        if has(DeviceKind::Plic)
+            && (has(DeviceKind::AplicS) || has(DeviceKind::ImsicM) || has(DeviceKind::ImsicS))
+        {
+            return invalid("AIA devices cannot be combined with a PLIC");
+        }
+        if count(DeviceKind::Clint) + count(DeviceKind::AclintMtimer) != 1 {
+            return invalid("exactly one CLINT or ACLINT MTIMER is required");
+        }
+        if count(DeviceKind::Clint) + count(DeviceKind::AclintMswi) != 1 {
+            return invalid("exactly one CLINT or ACLINT MSWI is required");
+        }
+        if count(DeviceKind::AclintSswi) > 1 {
+            return invalid("at most one ACLINT SSWI is supported");
+        }
+        for device in &self.devices {
+            let uart = matches!(device.kind, DeviceKind::Uart | DeviceKind::SifiveUart);
+            if uart && device.irq.is_none() {
+                return invalid("a UART needs an irq number");
+            }
+            if !uart && device.trigger != Trigger::Level {
+                return invalid("only devices with an interrupt line have a trigger");
+            }
+        }
+        if count(DeviceKind::Rom) > 1 {
+            return invalid("at most one boot ROM is supported");
+        }
+        Ok(())
+    }
+
+    /// Address the hart starts executing at: the boot ROM if there is one,
+    /// otherwise the start of the first memory region.
+    pub fn reset_vector(&self) -> u64 {
+        self.devices
+            .iter()
+            .find(|device| device.kind == DeviceKind::Rom)
+            .map(|rom| rom.base)
+            .unwrap_or(self.memory[0].base)
+    }
+
+    /// The interrupt controller the described machine uses.
+    pub fn irqchip(&self) -> IrqChip {
+        if self
+            .devices
+            .iter()
+            .any(|device| device.kind == DeviceKind::AplicM)
+        {
+            IrqChip::Aia
+        } else {
+            IrqChip::Plic
+        }
+    }
+
+    /// Replace the interrupt controller with `chip` at its default addresses.
+    pub fn set_irqchip(&mut self, chip: IrqChip) {
+        self.devices.retain(|device| {
+            !matches!(
+                device.kind,
+                DeviceKind::Plic
+                    | DeviceKind::AplicM
+                    | DeviceKind::AplicS
+                    | DeviceKind::ImsicM
+                    | DeviceKind::ImsicS
+            )
+        });
+        match chip {
+            IrqChip::Plic => {
+                self.devices
+                    .push(DeviceConfig::new(DeviceKind::Plic, PLIC_BASE, None));
+            }
+            IrqChip::Aia => {
+                self.devices.extend([
+                    DeviceConfig::new(DeviceKind::AplicM, APLIC_M_BASE, None),
+                    DeviceConfig::new(DeviceKind::AplicS, APLIC_S_BASE, None),
+                    DeviceConfig::new(DeviceKind::ImsicM, IMSIC_M_BASE, None),
+                    DeviceConfig::new(DeviceKind::ImsicS, IMSIC_S_BASE, None),
+                ]);
+            }
+        }
+    }
+
+    /// Replace the timer and software interrupt devices with `layout` at
+    /// its default addresses.
+    pub fn set_clint_layout(&mut self, layout: ClintLayout) {
+        self.devices.retain(|device| {
+            !matches!(
+                device.kind,
+                DeviceKind::Clint
+                    | DeviceKind::AclintMswi
+                    | DeviceKind::AclintMtimer
+                    | DeviceKind::AclintSswi
+            )
+        });
+        match layout {
+            ClintLayout::Sifive => {
+                self.devices
+                    .push(DeviceConfig::new(DeviceKind::Clint, CLINT_BASE, None));
+            }
+            ClintLayout::Aclint => {
+                self.devices.extend([
+                    DeviceConfig::new(DeviceKind::AclintMswi, ACLINT_MSWI_BASE, None),
+                    DeviceConfig::new(DeviceKind::AclintMtimer, ACLINT_MTIMER_BASE, None),
+                    DeviceConfig::new(DeviceKind::AclintSswi, ACLINT_SSWI_BASE, None),
+                ]);
+            }
+        }
+    }
+
+    /// The misa value for the ISA string. Only RV64 with the single-letter
+    /// extensions the emulator implements is accepted. Multi-letter
+    /// extensions after the first underscore are ignored.
+    pub fn misa(&self) -> Result<u64, ConfigError> {
+        let isa = self.isa.to_ascii_lowercase();
+        let letters = isa
+            .strip_prefix("rv64")
+            .ok_or_else(|| ConfigError::Invalid(format!("unsupported ISA string: {}", self.isa)))?;
+        let letters = letters.split('_').next().unwrap_or("");
+        // MXL = 2: XLEN is 64.
+        let mut misa = 2 << 62;
+        for letter in letters.chars() {
+            if !IMPLEMENTED_EXTENSIONS.contains(letter) {
+                return Err(ConfigError::Invalid(format!(
+                    "unsupported ISA extension '{}' in {}",
+                    letter, self.isa
+                )));
+            }
+            misa |= 1 << (letter as u64 - 'a' as u64);
+        }
+        Ok(misa)
+    }
+}
@@ -1,61 +1,191 @@
+mod aplic;
 mod bus;
 mod clint;
 mod cpu;
 mod csr;
+mod device;
 mod dram;
 mod exception;
+mod file_memory;
+mod hpm;
+mod htif;
+mod imsic;
 mod interrupt;
+mod irq;
 mod lib;
+mod machine;
 mod plic;
+mod pmp;
+mod power;
+mod profile;
+mod rom;
+mod run;
+mod scheduler;
+mod sifive_test;
+mod sifive_uart;
 mod uart;
+mod virtio;
 
+use clint::{ClintLayout, ClockMode};
 use cpu::*;
 use exception::*;
 use interrupt::*;
+use machine::*;
+use run::*;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
 use std::io::prelude::*;
+use std::process;
+use std::time::Duration;
+
+const USAGE: &str =
+    "Usage: rrvemu [--machine <file.toml> | --profile default|virt|spike|sifive_u] \
+                     [--memory <size>] [--dtb <file.dtb>] [--irqchip plic|aia] \
+                     [--clint sifive|aclint] [--clock wall|virtual] [--ticks-per-insn N] \
+                     [--misaligned hardware|trap|trap-on-boundary] \
+                     [--halt-on <exception>,...|m-mode|double-fault] [--max-insns N] [--timeout SECONDS] [--json <file>|-] \
+                     <filename>";
 
 fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Usage: rrvemu <filename>");
+    // The machine description is read first so that the other options
+    // override it regardless of their position.
+    let option = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .map(|i| args.get(i + 1).expect(USAGE))
+    };
+    let mut config = match (option("--machine"), option("--profile")) {
+        (Some(path), None) => MachineConfig::from_file(path)
+            .unwrap_or_else(|e| panic!("Invalid machine description {}: {}", path, e)),
+        (None, Some(name)) => profile::profile(name).unwrap_or_else(|| {
+            panic!(
+                "Unknown profile: {} (expected one of {})",
+                name,
+                profile::PROFILES.join(", ")
+            )
+        }),
+        (None, None) => MachineConfig::default(),
+        (Some(_), Some(_)) => panic!("--machine and --profile cannot be combined"),
+    };
+    let mut filename = None;
+    let mut limits = RunLimits::default();
+    let mut json = None;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--machine" | "--profile" if i + 1 < args.len() => i += 1,
+            "--memory" if i + 1 < args.len() => {
+                i += 1;
+                // Sets the size of the first RAM bank, which holds the binary.
+                config.memory[0].size = parse_size(&args[i])
+                    .unwrap_or_else(|| panic!("Invalid memory size: {}", args[i]));
+            }
+            "--dtb" if i + 1 < args.len() => {
+                i += 1;
+                config.dtb = Some(args[i].clone());
+            }
+            "--halt-on" if i + 1 < args.len() => {
+                i += 1;
+                config.halt = HaltPolicy::from_names(args[i].split(','))
+                    .unwrap_or_else(|e| panic!("Invalid halt policy: {}", e));
+            }
+            "--max-insns" if i + 1 < args.len() => {
+                i += 1;
+                limits.max_instructions = Some(
+                    args[i]
+                        .parse()
+                        .unwrap_or_else(|_| panic!("Invalid instruction count: {}", args[i])),
+                );
+            }
+            "--timeout" if i + 1 < args.len() => {
+                i += 1;
+                let seconds: f64 = args[i]
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid timeout: {}", args[i]));
+                limits.timeout = Some(Duration::from_secs_f64(seconds));
+            }
+            "--json" if i + 1 < args.len() => {
+                i += 1;
+                json = Some(args[i].clone());
+            }
+            "--irqchip" if i + 1 < args.len() => {
+                i += 1;
+                config.set_irqchip(
+                    IrqChip::from_name(&args[i])
+                        .unwrap_or_else(|| panic!("Unknown interrupt controller: {}", args[i])),
+                );
+            }
+            "--clint" if i + 1 < args.len() => {
+                i += 1;
+                config.set_clint_layout(
+                    ClintLayout::from_name(&args[i])
+                        .unwrap_or_else(|| panic!("Unknown CLINT layout: {}", args[i])),
+                );
+            }
+            "--clock" if i + 1 < args.len() => {
+                i += 1;
+                config.clock = match args[i].as_str() {
+                    "wall" => ClockMode::WallClock,
+                    "virtual" => ClockMode::Virtual {
+                        ticks_per_instruction: 1,
+                    },
+                    _ => panic!("Unknown clock mode: {}", args[i]),
+                };
+            }
+            "--ticks-per-insn" if i + 1 < args.len() => {
+                i += 1;
+                let ticks_per_instruction = args[i]
+                    .parse()
+                    .ok()
+                    .filter(|&ticks| ticks > 0)
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "Invalid tick count: {} (must be greater than zero)",
+                            args[i]
+                        )
+                    });
+                config.clock = ClockMode::Virtual {
+                    ticks_per_instruction,
+                };
+            }
+            "--misaligned" if i + 1 < args.len() => {
+                i += 1;
+                config.misaligned = MisalignedPolicy::from_name(&args[i])
+                    .unwrap_or_else(|| panic!("Unknown misaligned access policy: {}", args[i]));
+            }
+            arg if filename.is_none() && !arg.starts_with("--") => filename = Some(arg.to_string()),
+            _ => panic!("{}", USAGE),
+        }
+        i += 1;
     }
-    let mut file = File::open(&args[1])?;
+    let filename = filename.expect(USAGE);
+    let mut file = File::open(&filename)?;
     let mut binary = Vec::new();
     file.read_to_end(&mut binary)?;
-    let timer_freq = 650000;
-    let mut cpu = Cpu::new(timer_freq, binary);
+    let mut cpu = Cpu::new(&config, binary);
 
-    loop {
-        let inst = match cpu.fetch() {
-            Ok(inst) => inst,
-            Err(e) => {
-                cpu.handle_exception(e);
-                if e.is_fatal() {
-                    println!("Failed to fetch instruction: {:?}", e);
-                    break;
-                }
-                continue;
-            }
-        };
-        match cpu.execute(inst) {
-            Ok(_) => {}
-            Err(e) => {
-                if e.is_fatal() {
-                    println!("Failed to execute instruction: {:?}", e);
-                    break;
-                } else {
-                    cpu.handle_exception(e);
-                }
-            }
+    let outcome = cpu.run(&limits);
+    io::stdout().flush()?;
+    match outcome {
+        RunOutcome::Fault { exception, .. } => {
+            eprintln!("Halted on exception: {:?}", exception);
+            cpu.print_registers();
         }
-        match cpu.check_interrupt() {
-            Some(interrupt) => cpu.handle_interrupt(interrupt),
-            None => (),
+        RunOutcome::InstructionLimit => eprintln!("Instruction limit reached"),
+        RunOutcome::Timeout => eprintln!("Time limit reached"),
+        RunOutcome::PowerOff { .. } => (),
+    }
+    if let Some(path) = json {
+        let report = outcome.to_json(cpu.retired);
+        // Status reports go to stderr, apart from the guest console output.
+        if path == "-" {
+            eprintln!("{}", report);
+        } else {
+            fs::write(&path, report + "\n")?;
         }
     }
-    cpu.print_registers();
-    Ok(())
+    io::stdout().flush()?;
+    process::exit(outcome.exit_code());
 }
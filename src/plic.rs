@@ -1,17 +1,63 @@
+use crate::device::Device;
 use crate::exception::*;
+use crate::irq::InterruptSink;
 use crate::lib::address::*;
-use crate::uart::UART_IRQ;
+use serde::Deserialize;
 
-pub const INTERRUPT_PRIORITY: u64 = PLIC_BASE + 0x00_0000;
-pub const INTERRUPT_PENDING: u64 = PLIC_BASE + 0x00_1000;
-pub const INTERRUPT_ENABLES: u64 = PLIC_BASE + 0x00_2000;
-pub const PRIORITY_THRESHOLD: u64 = PLIC_BASE + 0x20_0000;
-pub const INTERRUPT_CLAIM: u64 = PLIC_BASE + 0x20_0004;
-pub const INTERRUPT_COMPLETION: u64 = PLIC_BASE + 0x20_0004;
+// Register offsets.
+pub const INTERRUPT_PRIORITY: u64 = 0x00_0000;
+pub const INTERRUPT_PENDING: u64 = 0x00_1000;
+pub const INTERRUPT_ENABLES: u64 = 0x00_2000;
+pub const PRIORITY_THRESHOLD: u64 = 0x20_0000;
+pub const INTERRUPT_CLAIM: u64 = 0x20_0004;
+pub const INTERRUPT_COMPLETION: u64 = 0x20_0004;
+
+/// Number of interrupt contexts: M-mode and S-mode of a single hart.
+pub const PLIC_CONTEXTS: u64 = 2;
+
+/// How the gateway of a source turns its interrupt line into requests.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    /// A request is pending for as long as the line is asserted.
+    #[default]
+    Level,
+    /// Every rising edge or pulse is a request. Edges that arrive while the
+    /// source is in service are queued and forwarded one per completion.
+    Edge,
+}
+
+/// The interrupt gateway of a single source.
+///
+/// A gateway forwards at most one request to the PLIC core at a time. Once a
+/// request has been forwarded, no new request is sent until the interrupt
+/// handler signals completion for that source.
+pub struct Gateway {
+    irq: u64,
+    trigger: Trigger,
+    // Current level of the interrupt line (level-triggered sources).
+    asserted: bool,
+    // A request has been forwarded and is either pending or being serviced.
+    in_flight: bool,
+    // Edges received while a request was in flight (edge-triggered sources).
+    queued: u64,
+}
+
+impl Gateway {
+    pub fn new(irq: u64, trigger: Trigger) -> Self {
+        Self {
+            irq,
+            trigger,
+            asserted: false,
+            in_flight: false,
+            queued: 0,
+        }
+    }
+}
 
 pub struct Plic {
     plic: [u8; PLIC_SIZE as usize],
-    peripherals_irq: Vec<u64>,
+    gateways: Vec<Gateway>,
 }
 
 impl Plic {
@@ -19,66 +65,68 @@ impl Plic {
         let plic = [0u8; PLIC_SIZE as usize];
         Self {
             plic,
-            peripherals_irq: Vec::new(),
+            gateways: Vec::new(),
         }
     }
 
-    pub fn add_irq(&mut self, irq: u64) {
-        self.peripherals_irq.push(irq);
+    pub fn add_irq(&mut self, irq: u64, trigger: Trigger) {
+        self.gateways.push(Gateway::new(irq, trigger));
+    }
+
+    fn gateway(&mut self, irq: u64) -> Option<&mut Gateway> {
+        self.gateways.iter_mut().find(|gateway| gateway.irq == irq)
     }
 
     pub fn set_pending(&mut self, irq: u64) {
         let index = irq as usize / 8;
         let offset = irq as usize % 8;
-        self.plic[(INTERRUPT_PENDING - PLIC_BASE) as usize + index] |= 1 << offset;
+        self.plic[INTERRUPT_PENDING as usize + index] |= 1 << offset;
     }
 
     pub fn clear_pending(&mut self, irq: u64) {
         let index = irq as usize / 8;
         let offset = irq as usize % 8;
-        self.plic[(INTERRUPT_PENDING - PLIC_BASE) as usize + index] &= !(1 << offset);
+        self.plic[INTERRUPT_PENDING as usize + index] &= !(1 << offset);
     }
 
     pub fn get_source_priority(&self, irq: u64) -> u64 {
         let irq_address = INTERRUPT_PRIORITY + irq * 4;
-        return self.load(irq_address, 32).unwrap();
+        self.read_register(irq_address, 32)
     }
 
     pub fn get_source_pending(&self, irq: u64) -> bool {
         let index = irq / 8;
         let offset = irq % 8;
-        self.load(INTERRUPT_PENDING + index, 8).unwrap() >> offset & 0b1 != 0
+        self.read_register(INTERRUPT_PENDING + index, 8) >> offset & 0b1 != 0
     }
 
-    pub fn get_source_enable(&self, irq: u64, hart: u64) -> bool {
-        let enable_address = INTERRUPT_ENABLES + 0x80 * hart;
+    pub fn get_source_enable(&self, irq: u64, context: u64) -> bool {
+        let enable_address = INTERRUPT_ENABLES + 0x80 * context;
         let index = irq / 8;
         let offset = irq % 8;
-        self.load(enable_address + index, 8).unwrap() >> offset & 0b1 != 0
+        self.read_register(enable_address + index, 8) >> offset & 0b1 != 0
     }
 
-    pub fn get_hart_priority(&self, hart: u64) -> u64 {
-        let hart_address = PRIORITY_THRESHOLD + hart * 0x1000;
-        return self.load(hart_address, 32).unwrap();
+    pub fn get_hart_priority(&self, context: u64) -> u64 {
+        let hart_address = PRIORITY_THRESHOLD + context * 0x1000;
+        self.read_register(hart_address, 32)
     }
 
-    pub fn check_pending(&self) -> Option<u64> {
+    pub fn check_pending(&self, context: u64) -> Option<u64> {
         let mut highest_priority_irq = None; // Stores the IRQ with the highest priority found so far.
         let mut highest_priority = 0; // Tracks the highest priority among the pending IRQs.
 
-        // Single-core mode: hardcode hart 1 (Supervisor mode).
-        let hart = 1;
-
-        // Retrieve the priority threshold for hart 1.
-        let hart_threshold = self.get_hart_priority(hart);
+        // Retrieve the priority threshold for the context.
+        let hart_threshold = self.get_hart_priority(context);
 
         // Iterate through all registered IRQs.
-        for &irq in &self.peripherals_irq {
+        for gateway in &self.gateways {
+            let irq = gateway.irq;
             // Check if the IRQ is in a pending state.
             let is_pending = self.get_source_pending(irq);
 
-            // Check if hart 1 has enabled this IRQ.
-            let is_enable = self.get_source_enable(irq, hart);
+            // Check if the context has enabled this IRQ.
+            let is_enable = self.get_source_enable(irq, context);
 
             // Skip this IRQ if it is not pending or not enabled.
             if !(is_pending && is_enable) {
@@ -101,43 +149,156 @@ impl Plic {
         highest_priority_irq
     }
 
-    pub fn claim(&mut self) -> Option<u64> {
+    pub fn claim(&mut self, context: u64) -> Option<u64> {
         // First, check if there are any pending interrupts
-        if let Some(irq) = self.check_pending() {
+        if let Some(irq) = self.check_pending(context) {
             // Found a pending interrupt, now claim it
-            // Clear the pending status of this interrupt, as it is now being processed
-            let index = (irq / 8) as usize;
-            let bit = (irq % 8) as u8;
-            self.plic[(INTERRUPT_PENDING - PLIC_BASE) as usize + index] &= !(1 << bit);
-            self.store(INTERRUPT_CLAIM + 0x1000, 32, irq).unwrap();
+            // Clear the pending status of this interrupt, as it is now being processed.
+            // The gateway keeps the request in flight until completion.
+            self.clear_pending(irq);
             return Some(irq);
         }
         // If no interrupts are pending, return None
         None
     }
 
-    pub fn completion(&mut self, irq: u64) {}
+    pub fn completion(&mut self, context: u64, irq: u64) {
+        // If the completion ID does not match an interrupt source that is
+        // currently enabled for the target, the completion is silently ignored.
+        if !self.get_source_enable(irq, context) {
+            return;
+        }
+        let forward = match self.gateway(irq) {
+            Some(gateway) if gateway.in_flight => {
+                gateway.in_flight = false;
+                match gateway.trigger {
+                    // A source that is still asserted raises a new request.
+                    Trigger::Level => gateway.asserted,
+                    // Edges that arrived while the source was in service are
+                    // forwarded one at a time.
+                    Trigger::Edge if gateway.queued > 0 => {
+                        gateway.queued -= 1;
+                        true
+                    }
+                    Trigger::Edge => false,
+                }
+            }
+            _ => false,
+        };
+        if forward {
+            self.forward(irq);
+        }
+    }
+
+    /// Forward a new request from the gateway of `irq` to the PLIC core.
+    fn forward(&mut self, irq: u64) {
+        if let Some(gateway) = self.gateway(irq) {
+            gateway.in_flight = true;
+        }
+        self.set_pending(irq);
+    }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if size % 8 != 0 {
-            return Err(Exception::LoadAccessFault(addr));
+    // Returns the context addressed by a claim/completion register access.
+    fn claim_context(offset: u64) -> Option<u64> {
+        if offset < INTERRUPT_CLAIM {
+            return None;
+        }
+        let offset = offset - INTERRUPT_CLAIM;
+        let context = offset / 0x1000;
+        if offset.is_multiple_of(0x1000) && context < PLIC_CONTEXTS {
+            Some(context)
+        } else {
+            None
         }
-        let index = (addr - PLIC_BASE) as usize;
+    }
+
+    fn read_register(&self, offset: u64, size: u64) -> u64 {
+        let index = offset as usize;
         let mut value = self.plic[index] as u64;
         for i in 1..size / 8 {
             value |= (self.plic[index + i as usize] as u64) << (i * 8);
         }
-        Ok(value)
+        value
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if !size.is_multiple_of(8) || offset + size / 8 > PLIC_SIZE {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        // Reading the claim register claims the highest priority pending interrupt.
+        if let Some(context) = Self::claim_context(offset) {
+            return Ok(self.claim(context).unwrap_or(0));
+        }
+        Ok(self.read_register(offset, size))
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if size % 8 != 0 {
-            return Err(Exception::StoreAMOAccessFault(addr));
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if !size.is_multiple_of(8) || offset + size / 8 > PLIC_SIZE {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        // Writing the completion register signals the gateway that the
+        // handler has finished servicing the interrupt.
+        if let Some(context) = Self::claim_context(offset) {
+            self.completion(context, value & 0xffff_ffff);
+            return Ok(());
         }
-        let index = (addr - PLIC_BASE) as usize;
+        let index = offset as usize;
         for i in 0..size / 8 {
             self.plic[index + i as usize] = (value >> (i * 8) & 0xff) as u8;
         }
         Ok(())
     }
+
+    fn reset(&mut self) {
+        self.plic = [0u8; PLIC_SIZE as usize];
+        for gateway in &mut self.gateways {
+            gateway.asserted = false;
+            gateway.in_flight = false;
+            gateway.queued = 0;
+        }
+    }
+}
+
+impl InterruptSink for Plic {
+    fn set_level(&mut self, irq: u64, level: bool) {
+        let forward = match self.gateway(irq) {
+            Some(gateway) => {
+                let rising = level && !gateway.asserted;
+                gateway.asserted = level;
+                match gateway.trigger {
+                    Trigger::Level => level && !gateway.in_flight,
+                    // An edge-triggered source only reacts to a rising edge.
+                    Trigger::Edge if rising => {
+                        if gateway.in_flight {
+                            gateway.queued += 1;
+                            false
+                        } else {
+                            true
+                        }
+                    }
+                    Trigger::Edge => false,
+                }
+            }
+            None => false,
+        };
+        if forward {
+            self.forward(irq);
+        }
+    }
+
+    fn pulse(&mut self, irq: u64) {
+        let forward = match self.gateway(irq) {
+            Some(gateway) if gateway.in_flight => {
+                gateway.queued += 1;
+                false
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if forward {
+            self.forward(irq);
+        }
+    }
 }
@@ -0,0 +1,266 @@
+use crate::cpu::Mode;
+use crate::exception::*;
+use crate::lib::address::*;
+
+/// Number of implemented PMP entries. The remaining entries of the 64
+/// architectural ones are read-only zero.
+pub const PMP_ENTRIES: usize = 16;
+
+// pmpcfg fields.
+pub const PMPCFG_R: u8 = 1 << 0;
+pub const PMPCFG_W: u8 = 1 << 1;
+pub const PMPCFG_X: u8 = 1 << 2;
+pub const PMPCFG_A: u8 = 0b11 << 3;
+pub const PMPCFG_L: u8 = 1 << 7;
+
+// mseccfg fields (Smepmp).
+/// Machine Mode Lockdown.
+pub const MSECCFG_MML: u64 = 1 << 0;
+/// Machine Mode Whitelist Policy.
+pub const MSECCFG_MMWP: u64 = 1 << 1;
+/// Rule Locking Bypass.
+pub const MSECCFG_RLB: u64 = 1 << 2;
+
+// Values of pmpcfg.A.
+const A_OFF: u8 = 0;
+const A_TOR: u8 = 1;
+const A_NA4: u8 = 2;
+const A_NAPOT: u8 = 3;
+
+// pmpaddr holds bits 55:2 of a physical address.
+const PMPADDR_MASK: u64 = (1 << 54) - 1;
+
+/// The kind of a physical memory access.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn fault(self, addr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionAccessFault(addr),
+            Access::Load => Exception::LoadAccessFault(addr),
+            Access::Store => Exception::StoreAMOAccessFault(addr),
+        }
+    }
+
+    fn permission(self) -> u8 {
+        match self {
+            Access::Fetch => PMPCFG_X,
+            Access::Load => PMPCFG_R,
+            Access::Store => PMPCFG_W,
+        }
+    }
+}
+
+/// Physical Memory Protection unit of a hart.
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [u64; PMP_ENTRIES],
+    // Address range [start, end) matched by each entry, decoded on writes.
+    range: [(u64, u64); PMP_ENTRIES],
+    mseccfg: u64,
+}
+
+impl Pmp {
+    pub fn new() -> Self {
+        Self {
+            cfg: [0; PMP_ENTRIES],
+            addr: [0; PMP_ENTRIES],
+            range: [(0, 0); PMP_ENTRIES],
+            mseccfg: 0,
+        }
+    }
+
+    fn locked(&self, i: usize) -> bool {
+        self.cfg[i] & PMPCFG_L != 0
+    }
+
+    // Whether writes to entry `i` are ignored. Rule Locking Bypass allows
+    // locked entries to be modified.
+    fn write_locked(&self, i: usize) -> bool {
+        self.locked(i) && self.mseccfg & MSECCFG_RLB == 0
+    }
+
+    fn mml(&self) -> bool {
+        self.mseccfg & MSECCFG_MML != 0
+    }
+
+    fn mode(&self, i: usize) -> u8 {
+        (self.cfg[i] & PMPCFG_A) >> 3
+    }
+
+    fn decode(&mut self) {
+        for i in 0..PMP_ENTRIES {
+            let addr = self.addr[i];
+            self.range[i] = match self.mode(i) {
+                A_TOR => {
+                    let start = if i == 0 { 0 } else { self.addr[i - 1] << 2 };
+                    (start, addr << 2)
+                }
+                A_NA4 => (addr << 2, (addr << 2) + 4),
+                A_NAPOT => {
+                    // pmpaddr = base/4 | (size/8 - 1)
+                    let ones = (!addr).trailing_zeros();
+                    let start = (addr & !((1 << ones) - 1)) << 2;
+                    (start, start + (1 << (ones + 3)))
+                }
+                _ => (0, 0),
+            };
+        }
+    }
+
+    /// Read a PMP CSR. Returns None for every other CSR.
+    pub fn csr_load(&self, addr: u64) -> Option<u64> {
+        match addr {
+            MSECCFG => Some(self.mseccfg),
+            PMPCFG0..=PMPCFG15 => {
+                // On RV64 the odd-numbered pmpcfg registers do not exist.
+                if !addr.is_multiple_of(2) {
+                    return Some(0);
+                }
+                let first = (addr - PMPCFG0) as usize * 4;
+                let mut value = 0;
+                for (byte, i) in (first..first + 8).enumerate() {
+                    if i < PMP_ENTRIES {
+                        value |= (self.cfg[i] as u64) << (byte * 8);
+                    }
+                }
+                Some(value)
+            }
+            PMPADDR0..=PMPADDR63 => {
+                let i = (addr - PMPADDR0) as usize;
+                Some(self.addr.get(i).copied().unwrap_or(0))
+            }
+            _ => None,
+        }
+    }
+
+    /// Write a PMP CSR. Returns false for every other CSR.
+    pub fn csr_store(&mut self, addr: u64, value: u64) -> bool {
+        match addr {
+            MSECCFG => {
+                // MML and MMWP are sticky until reset.
+                let mut mseccfg = (self.mseccfg | value) & (MSECCFG_MML | MSECCFG_MMWP);
+                // RLB stays 0 once an entry is locked while RLB is 0.
+                let frozen =
+                    self.mseccfg & MSECCFG_RLB == 0 && (0..PMP_ENTRIES).any(|i| self.locked(i));
+                if !frozen {
+                    mseccfg |= value & MSECCFG_RLB;
+                }
+                self.mseccfg = mseccfg;
+                return true;
+            }
+            PMPCFG0..=PMPCFG15 => {
+                if !addr.is_multiple_of(2) {
+                    return true;
+                }
+                let first = (addr - PMPCFG0) as usize * 4;
+                for (byte, i) in (first..first + 8).enumerate() {
+                    if i >= PMP_ENTRIES || self.write_locked(i) {
+                        continue;
+                    }
+                    let mut cfg = (value >> (byte * 8)) as u8 & !(0b11 << 5);
+                    if self.mml() {
+                        // With MML set, executable M-mode-only and locked
+                        // shared-code rules can only be added with RLB set.
+                        let executable = Self::mml_permission(cfg, true) & PMPCFG_X != 0;
+                        if cfg & PMPCFG_L != 0 && executable && self.mseccfg & MSECCFG_RLB == 0 {
+                            continue;
+                        }
+                    } else if cfg & (PMPCFG_R | PMPCFG_W) == PMPCFG_W {
+                        // R=0, W=1 is reserved without MML.
+                        cfg &= !PMPCFG_W;
+                    }
+                    self.cfg[i] = cfg;
+                }
+            }
+            PMPADDR0..=PMPADDR63 => {
+                let i = (addr - PMPADDR0) as usize;
+                if i >= PMP_ENTRIES || self.write_locked(i) {
+                    return true;
+                }
+                // A locked TOR entry also locks the address below it.
+                if i + 1 < PMP_ENTRIES && self.write_locked(i + 1) && self.mode(i + 1) == A_TOR {
+                    return true;
+                }
+                self.addr[i] = value & PMPADDR_MASK;
+            }
+            _ => return false,
+        }
+        self.decode();
+        true
+    }
+
+    // Permissions an entry grants under Machine Mode Lockdown. Locked
+    // entries are M-mode-only rules, unlocked ones S/U-mode-only rules,
+    // except for the shared-region encodings.
+    fn mml_permission(cfg: u8, machine: bool) -> u8 {
+        let (r, w, x) = (PMPCFG_R, PMPCFG_W, PMPCFG_X);
+        let l = cfg & PMPCFG_L != 0;
+        match (l, cfg & (r | w | x)) {
+            (false, p) if p == w => {
+                if machine {
+                    r | w
+                } else {
+                    r
+                }
+            }
+            (false, p) if p == w | x => r | w,
+            (true, p) if p == w => x,
+            (true, p) if p == w | x => {
+                if machine {
+                    r | x
+                } else {
+                    x
+                }
+            }
+            (true, p) if p == r | w | x => r,
+            (l, p) if l == machine => p,
+            _ => 0,
+        }
+    }
+
+    /// Check an access of `size` bits at `addr` made with privilege `mode`.
+    ///
+    /// The lowest-numbered entry that matches any byte of the access decides:
+    /// it must match every byte and grant the permission. Without Machine
+    /// Mode Lockdown, M-mode is only bound by locked entries. An S-mode or
+    /// U-mode access that matches no entry fails, and so does an M-mode
+    /// access under MMWP, or an M-mode fetch under MML.
+    pub fn check(&self, addr: u64, size: u64, access: Access, mode: Mode) -> Result<(), Exception> {
+        let machine = mode == Mode::Machine;
+        let end = addr.saturating_add(size / 8);
+        for i in 0..PMP_ENTRIES {
+            let (start, stop) = self.range[i];
+            if self.mode(i) == A_OFF || start >= stop || end <= start || stop <= addr {
+                continue;
+            }
+            if addr < start || stop < end {
+                return Err(access.fault(addr));
+            }
+            let permission = if self.mml() {
+                Self::mml_permission(self.cfg[i], machine)
+            } else if machine && !self.locked(i) {
+                return Ok(());
+            } else {
+                self.cfg[i]
+            };
+            return if permission & access.permission() != 0 {
+                Ok(())
+            } else {
+                Err(access.fault(addr))
+            };
+        }
+        let denied =
+            !machine || self.mseccfg & MSECCFG_MMWP != 0 || (self.mml() && access == Access::Fetch);
+        if denied {
+            Err(access.fault(addr))
+        } else {
+            Ok(())
+        }
+    }
+}
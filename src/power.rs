@@ -0,0 +1,33 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// A request from the guest to stop or restart the machine.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PowerRequest {
+    /// Power off. The emulator exits with the given code.
+    PowerOff(i32),
+    /// Restart the machine in place.
+    Reset,
+}
+
+/// The line devices raise power requests on. The run loop acts on a request
+/// once the instruction that made it has completed.
+#[derive(Clone, Default)]
+pub struct PowerControl {
+    request: Rc<Cell<Option<PowerRequest>>>,
+}
+
+impl PowerControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn request(&self, request: PowerRequest) {
+        self.request.set(Some(request));
+    }
+
+    /// Take the pending request, if any.
+    pub fn take(&self) -> Option<PowerRequest> {
+        self.request.take()
+    }
+}
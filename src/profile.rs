@@ -0,0 +1,81 @@
+use crate::clint::ClintLayout;
+use crate::lib::address::*;
+use crate::machine::*;
+
+/// Names of the built-in machines.
+pub const PROFILES: [&str; 4] = ["default", "virt", "spike", "sifive_u"];
+
+// Spike finds tohost and fromhost through the symbol table of the ELF it
+// loads. Flat binaries have none, so they must be linked against this
+// address instead.
+pub const SPIKE_HTIF_BASE: u64 = 0x4000_0000;
+
+/// A built-in machine by name.
+pub fn profile(name: &str) -> Option<MachineConfig> {
+    match name {
+        "default" => Some(MachineConfig::default()),
+        "virt" => Some(qemu_virt()),
+        "spike" => Some(spike()),
+        "sifive_u" => Some(sifive_u()),
+        _ => None,
+    }
+}
+
+// A machine with no devices. ISA and clock keep their defaults: only the
+// extensions the emulator implements are reported in misa.
+fn empty(timer_freq: u64, memory_size: u64) -> MachineConfig {
+    MachineConfig {
+        timer_freq,
+        memory: vec![MemoryConfig::new(DRAM_BASE, memory_size)],
+        devices: Vec::new(),
+        ..Default::default()
+    }
+}
+
+/// The memory map of the QEMU `virt` board.
+pub fn qemu_virt() -> MachineConfig {
+    let mut config = empty(10_000_000, 128 * 1024 * 1024);
+    config.devices.extend([
+        DeviceConfig::new(DeviceKind::Rom, 0x1000, None),
+        DeviceConfig::new(DeviceKind::SifiveTest, SIFIVE_TEST_BASE, None),
+        DeviceConfig::new(DeviceKind::Uart, 0x1000_0000, Some(10)),
+    ]);
+    for slot in 0..8 {
+        config.devices.push(DeviceConfig::new(
+            DeviceKind::VirtioMmio,
+            0x1000_1000 + slot * 0x1000,
+            Some(1 + slot),
+        ));
+    }
+    config.set_irqchip(IrqChip::Plic);
+    config.set_clint_layout(ClintLayout::Sifive);
+    config
+}
+
+/// The memory map of the Spike ISA simulator.
+pub fn spike() -> MachineConfig {
+    let mut config = empty(10_000_000, 2 * 1024 * 1024 * 1024);
+    config.devices.extend([
+        DeviceConfig::new(DeviceKind::Rom, 0x1000, None),
+        DeviceConfig::new(DeviceKind::Uart, 0x1000_0000, Some(1)),
+        DeviceConfig::new(DeviceKind::Htif, SPIKE_HTIF_BASE, None),
+    ]);
+    config.set_irqchip(IrqChip::Plic);
+    config.set_clint_layout(ClintLayout::Sifive);
+    config
+}
+
+/// The memory map of the SiFive HiFive Unleashed (FU540), as modelled by the
+/// QEMU `sifive_u` board.
+pub fn sifive_u() -> MachineConfig {
+    let mut config = empty(1_000_000, 8 * 1024 * 1024 * 1024);
+    config.devices.extend([
+        DeviceConfig::new(DeviceKind::Rom, 0x1000, None),
+        DeviceConfig::new(DeviceKind::SifiveTest, 0x10_0000, None),
+        DeviceConfig::new(DeviceKind::SifiveUart, 0x1001_0000, Some(4)),
+        DeviceConfig::new(DeviceKind::SifiveUart, 0x1001_1000, Some(5)),
+    ]);
+    config.set_irqchip(IrqChip::Plic);
+    config.set_clint_layout(ClintLayout::Sifive);
+    config
+}
@@ -0,0 +1,66 @@
+use crate::device::{AmoSupport, Device, Pma};
+use crate::exception::*;
+
+/// Size of the boot ROM region.
+pub const ROM_SIZE: u64 = 0x1000;
+
+// Offsets of the firmware entry and device tree addresses in the reset stub.
+const ENTRY_OFFSET: usize = 0x18;
+const DTB_OFFSET: usize = 0x20;
+
+/// A read-only memory holding the reset vector.
+///
+/// The reset stub follows the QEMU convention: a0 holds the hart ID, a1 the
+/// address of the device tree (0 if there is none) and a2 is cleared, as no
+/// dynamic firmware information is passed. It then jumps to the firmware
+/// entry point.
+pub struct Rom {
+    rom: Vec<u8>,
+}
+
+impl Rom {
+    pub fn new(entry: u64, dtb: u64) -> Self {
+        let stub: [u32; 6] = [
+            0x0000_0297, // auipc t0, 0
+            0x0000_0613, // li    a2, 0
+            0xf140_2573, // csrr  a0, mhartid
+            0x0202_b583, // ld    a1, 32(t0)
+            0x0182_b283, // ld    t0, 24(t0)
+            0x0002_8067, // jr    t0
+        ];
+        let mut rom = vec![0u8; ROM_SIZE as usize];
+        for (i, word) in stub.iter().enumerate() {
+            rom[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        rom[ENTRY_OFFSET..ENTRY_OFFSET + 8].copy_from_slice(&entry.to_le_bytes());
+        rom[DTB_OFFSET..DTB_OFFSET + 8].copy_from_slice(&dtb.to_le_bytes());
+        Self { rom }
+    }
+}
+
+impl Device for Rom {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        let index = offset as usize;
+        if !size.is_multiple_of(8) || index + (size / 8) as usize > self.rom.len() {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        let mut value = 0;
+        for i in 0..(size / 8) as usize {
+            value |= (self.rom[index + i] as u64) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: u64, _size: u64, _value: u64) -> Result<(), Exception> {
+        Err(Exception::StoreAMOAccessFault(offset))
+    }
+
+    // The ROM cannot be written, so AMOs and LR/SC fault before reading.
+    fn pma(&self) -> Pma {
+        Pma {
+            amo: AmoSupport::None,
+            lrsc: false,
+            ..Pma::memory()
+        }
+    }
+}
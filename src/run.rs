@@ -0,0 +1,76 @@
+use crate::exception::*;
+use std::time::Duration;
+
+// Process exit codes of the outcomes the emulator itself decides. Guest exit
+// codes at or above the first of them are reported as GUEST_EXIT_MAX and
+// negative ones as 1, so that only a guest exiting with 0 passes.
+pub const EXIT_FAULT: i32 = 200;
+pub const EXIT_INSTRUCTION_LIMIT: i32 = 201;
+pub const EXIT_TIMEOUT: i32 = 202;
+pub const GUEST_EXIT_MAX: i32 = EXIT_FAULT - 1;
+
+/// Limits on how long the machine runs.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RunLimits {
+    /// Stop after this many retired instructions.
+    pub max_instructions: Option<u64>,
+    /// Stop after this much wall-clock time.
+    pub timeout: Option<Duration>,
+}
+
+/// How a run of the machine ended.
+#[derive(Debug, Clone, Copy)]
+pub enum RunOutcome {
+    /// The guest powered the machine off.
+    PowerOff { code: i32 },
+    /// The hart raised an exception the halt policy stops at.
+    Fault { exception: Exception, pc: u64 },
+    /// The instruction limit was reached.
+    InstructionLimit,
+    /// The time limit was reached.
+    Timeout,
+}
+
+impl RunOutcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RunOutcome::PowerOff { .. } => "power-off",
+            RunOutcome::Fault { .. } => "fault",
+            RunOutcome::InstructionLimit => "instruction-limit",
+            RunOutcome::Timeout => "timeout",
+        }
+    }
+
+    /// The exit status of the emulator process.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunOutcome::PowerOff { code: 0 } => 0,
+            RunOutcome::PowerOff { code } => (*code).clamp(1, GUEST_EXIT_MAX),
+            RunOutcome::Fault { .. } => EXIT_FAULT,
+            RunOutcome::InstructionLimit => EXIT_INSTRUCTION_LIMIT,
+            RunOutcome::Timeout => EXIT_TIMEOUT,
+        }
+    }
+
+    /// A one-line JSON description of the outcome.
+    pub fn to_json(self, instructions: u64) -> String {
+        let details = match self {
+            RunOutcome::PowerOff { code } => format!(",\"code\":{}", code),
+            RunOutcome::Fault { exception, pc } => format!(
+                ",\"exception\":\"{:?}\",\"cause\":{},\"tval\":{},\"pc\":{}",
+                exception,
+                exception.code(),
+                exception.value(),
+                pc
+            ),
+            _ => String::new(),
+        };
+        format!(
+            "{{\"outcome\":\"{}\",\"exit_code\":{},\"instructions\":{}{}}}",
+            self.name(),
+            self.exit_code(),
+            instructions,
+            details
+        )
+    }
+}
@@ -0,0 +1,99 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Interval, in mtime ticks, between two polls of the devices for host
+/// input such as the console.
+pub const DEVICE_POLL_INTERVAL: u64 = 10_000;
+
+/// Device events that need the interrupt state to be re-evaluated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Event {
+    /// mtime reaches mtimecmp.
+    MachineTimer,
+    /// time reaches stimecmp (Sstc).
+    SupervisorTimer,
+    /// Let the devices check for host input, such as console bytes.
+    DevicePoll,
+}
+
+/// A queue of device events keyed on mtime.
+///
+/// Instead of polling every device after each instruction, the CPU runs
+/// until the nearest event is due, or until an MMIO access, a CSR write or a
+/// trap may have changed the interrupt state.
+pub struct Scheduler {
+    queue: BinaryHeap<Reverse<(u64, Event)>>,
+    // Instructions left to retire before the nearest event is due.
+    countdown: u64,
+    // Interrupt state may have changed since the last check.
+    dirty: bool,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            queue: BinaryHeap::new(),
+            countdown: 0,
+            dirty: true,
+        }
+    }
+
+    /// Schedule `event` at mtime `deadline`, replacing an earlier schedule.
+    pub fn schedule(&mut self, event: Event, deadline: u64) {
+        self.cancel(event);
+        self.queue.push(Reverse((deadline, event)));
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.queue.retain(|Reverse((_, queued))| *queued != event);
+    }
+
+    pub fn is_scheduled(&self, event: Event) -> bool {
+        self.queue
+            .iter()
+            .any(|Reverse((_, queued))| *queued == event)
+    }
+
+    pub fn deadline(&self, event: Event) -> Option<u64> {
+        self.queue
+            .iter()
+            .find(|Reverse((_, queued))| *queued == event)
+            .map(|Reverse((deadline, _))| *deadline)
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.queue.peek().map(|Reverse((deadline, _))| *deadline)
+    }
+
+    /// Remove and return the next event that is due at mtime `now`.
+    pub fn pop_due(&mut self, now: u64) -> Option<Event> {
+        match self.queue.peek() {
+            Some(Reverse((deadline, _))) if *deadline <= now => {
+                self.queue.pop().map(|Reverse((_, event))| event)
+            }
+            _ => None,
+        }
+    }
+
+    /// Record that an MMIO access, a CSR write or a trap may have changed
+    /// the interrupt state.
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn retire(&mut self) {
+        self.countdown = self.countdown.saturating_sub(1);
+    }
+
+    /// Whether the interrupt state has to be checked before the next instruction.
+    pub fn due(&self) -> bool {
+        self.dirty || self.countdown == 0
+    }
+
+    /// Run `instructions` instructions before the next check, unless the
+    /// interrupt state changes first.
+    pub fn rearm(&mut self, instructions: u64) {
+        self.countdown = instructions;
+        self.dirty = false;
+    }
+}
@@ -0,0 +1,74 @@
+use crate::device::Device;
+use crate::exception::*;
+use crate::power::*;
+
+pub const SIFIVE_TEST_SIZE: u64 = 0x1000;
+
+// Values written to the finisher register. Bits 31:16 of a failure hold the
+// exit code.
+pub const FINISHER_FAIL: u64 = 0x3333;
+pub const FINISHER_PASS: u64 = 0x5555;
+pub const FINISHER_RESET: u64 = 0x7777;
+
+/// The SiFive test finisher, used by guests to power off or reset the
+/// machine.
+///
+/// The register also serves as the syscon of the generic poweroff and
+/// reboot drivers, described in the device tree as on the QEMU `virt`
+/// board:
+///
+/// ```text
+/// test: test@100000 {
+///     compatible = "sifive,test1", "sifive,test0", "syscon";
+///     reg = <0x0 0x100000 0x0 0x1000>;
+/// };
+/// poweroff {
+///     compatible = "syscon-poweroff";
+///     regmap = <&test>;
+///     offset = <0x0>;
+///     value = <0x5555>;
+/// };
+/// reboot {
+///     compatible = "syscon-reboot";
+///     regmap = <&test>;
+///     offset = <0x0>;
+///     value = <0x7777>;
+/// };
+/// ```
+pub struct SifiveTest {
+    power: PowerControl,
+}
+
+impl SifiveTest {
+    pub fn new(power: PowerControl) -> Self {
+        Self { power }
+    }
+}
+
+impl Device for SifiveTest {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        Ok(0)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        if offset != 0 {
+            return Ok(());
+        }
+        match value & 0xffff {
+            FINISHER_PASS => self.power.request(PowerRequest::PowerOff(0)),
+            FINISHER_FAIL => {
+                let code = (value >> 16 & 0xffff) as i32;
+                self.power.request(PowerRequest::PowerOff(code))
+            }
+            FINISHER_RESET => self.power.request(PowerRequest::Reset),
+            _ => (),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use crate::device::Device;
+use crate::exception::*;
+use crate::irq::IrqLine;
+use emu_nb_stdin::EmuNbStdin;
+use std::io::{self, Write};
+
+pub const SIFIVE_UART_SIZE: u64 = 0x1000;
+
+// Register offsets.
+pub const TXDATA: u64 = 0x00;
+pub const RXDATA: u64 = 0x04;
+pub const TXCTRL: u64 = 0x08;
+pub const RXCTRL: u64 = 0x0c;
+pub const IE: u64 = 0x10;
+pub const IP: u64 = 0x14;
+pub const DIV: u64 = 0x18;
+
+// rxdata.empty: no character has been received.
+const RXDATA_EMPTY: u64 = 1 << 31;
+// Watermark interrupt bits of ie and ip.
+const IP_TXWM: u64 = 1 << 0;
+const IP_RXWM: u64 = 1 << 1;
+
+/// The UART of SiFive SoCs.
+///
+/// The transmitter is never busy, and the receiver holds at most one
+/// character, which makes both FIFOs one entry deep.
+pub struct SifiveUart {
+    txctrl: u64,
+    rxctrl: u64,
+    ie: u64,
+    div: u64,
+    // Received character waiting in rxdata.
+    rx: Option<u8>,
+    in_fd: EmuNbStdin,
+    irq: IrqLine,
+}
+
+impl SifiveUart {
+    pub fn new(irq: IrqLine) -> Self {
+        Self {
+            txctrl: 0,
+            rxctrl: 0,
+            ie: 0,
+            div: 0,
+            rx: None,
+            in_fd: EmuNbStdin::new(),
+            irq,
+        }
+    }
+
+    // The watermark interrupts are pending while the transmit FIFO holds
+    // fewer entries than txctrl.txcnt, or the receive FIFO more entries than
+    // rxctrl.rxcnt.
+    fn ip(&self) -> u64 {
+        let mut ip = 0;
+        if (self.txctrl >> 16 & 0b111) > 0 {
+            ip |= IP_TXWM;
+        }
+        if self.rx.is_some() && (self.rxctrl >> 16 & 0b111) == 0 {
+            ip |= IP_RXWM;
+        }
+        ip
+    }
+
+    fn update_irq(&self) {
+        self.irq.set(self.ie & self.ip() != 0);
+    }
+}
+
+impl Device for SifiveUart {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        let value = match offset {
+            // The transmit FIFO is never full.
+            TXDATA => 0,
+            RXDATA => {
+                let value = match self.rx.take() {
+                    Some(byte) => byte as u64,
+                    None => RXDATA_EMPTY,
+                };
+                self.update_irq();
+                value
+            }
+            TXCTRL => self.txctrl,
+            RXCTRL => self.rxctrl,
+            IE => self.ie,
+            IP => self.ip(),
+            DIV => self.div,
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        match offset {
+            TXDATA => {
+                print!("{}", value as u8 as char);
+                io::stdout()
+                    .flush()
+                    .expect("Failed to flush stdout after writing to UART");
+            }
+            // txen, nstop and txcnt.
+            TXCTRL => self.txctrl = value & 0x0007_0003,
+            // rxen and rxcnt.
+            RXCTRL => self.rxctrl = value & 0x0007_0001,
+            IE => self.ie = value & (IP_TXWM | IP_RXWM),
+            DIV => self.div = value & 0xffff,
+            _ => {}
+        }
+        self.update_irq();
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.txctrl = 0;
+        self.rxctrl = 0;
+        self.ie = 0;
+        self.rx = None;
+        self.irq.lower();
+    }
+
+    fn check_interrupt(&mut self) {
+        if self.rx.is_none() && self.in_fd.poll() {
+            self.rx = self.in_fd.receive();
+            self.update_irq();
+        }
+    }
+}
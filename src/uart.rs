@@ -1,23 +1,23 @@
+use crate::device::{Device, Pma, WIDTH_8};
 use crate::exception::*;
+use crate::irq::IrqLine;
 use crate::lib::address::*;
-use crate::plic::*;
 use emu_nb_stdin::EmuNbStdin;
-use std::cell::RefCell;
 use std::io::{self, Write};
-use std::rc::Rc;
 
 pub const UART_IRQ: u64 = 10;
 
-pub const RHR: u64 = UART_BASE + 0b000;
-pub const THR: u64 = UART_BASE + 0b000;
-pub const _IER: u64 = UART_BASE + 0b001;
-pub const _ISR: u64 = UART_BASE + 0b010;
-pub const _FCR: u64 = UART_BASE + 0b010;
-pub const _LCR: u64 = UART_BASE + 0b011;
-pub const _MCR: u64 = UART_BASE + 0b100;
-pub const LSR: u64 = UART_BASE + 0b101;
-pub const _MSR: u64 = UART_BASE + 0b110;
-pub const _SPR: u64 = UART_BASE + 0b111;
+// Register offsets.
+pub const RHR: u64 = 0b000;
+pub const THR: u64 = 0b000;
+pub const _IER: u64 = 0b001;
+pub const _ISR: u64 = 0b010;
+pub const _FCR: u64 = 0b010;
+pub const _LCR: u64 = 0b011;
+pub const _MCR: u64 = 0b100;
+pub const LSR: u64 = 0b101;
+pub const _MSR: u64 = 0b110;
+pub const _SPR: u64 = 0b111;
 
 pub const LSR_DATA_READY: u8 = 1;
 pub const LSR_THR_EMPTY: u8 = 1 << 5;
@@ -25,47 +25,45 @@ pub const LSR_THR_EMPTY: u8 = 1 << 5;
 pub struct UART {
     uart: Vec<u8>,
     in_fd: EmuNbStdin,
-    plic: Rc<RefCell<Plic>>,
+    irq: IrqLine,
 }
 
 impl UART {
-    pub fn new(plic: Rc<RefCell<Plic>>) -> Self {
+    pub fn new(irq: IrqLine) -> Self {
         let mut uart = vec![0u8; UART_SIZE as usize];
-        uart[(LSR - UART_BASE) as usize] |= LSR_THR_EMPTY;
-        plic.borrow_mut().add_irq(UART_IRQ);
+        uart[LSR as usize] |= LSR_THR_EMPTY;
         Self {
             uart,
             in_fd: EmuNbStdin::new(),
-            plic,
-        }
-    }
-
-    pub fn check_interrupt(&mut self) {
-        if self.in_fd.poll() {
-            self.uart[(LSR - UART_BASE) as usize] |= LSR_DATA_READY;
-            self.plic.borrow_mut().set_pending(UART_IRQ);
+            irq,
         }
     }
+}
 
-    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+impl Device for UART {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
         if size != 8 {
-            return Err(Exception::LoadAccessFault(addr));
+            return Err(Exception::LoadAccessFault(offset));
         }
-        match addr {
+        match offset {
             RHR => {
-                self.uart[(LSR - UART_BASE) as usize] &= !LSR_DATA_READY;
+                self.uart[LSR as usize] &= !LSR_DATA_READY;
+                self.irq.lower();
                 if let Some(value) = self.in_fd.receive() {
                     Ok(value as u64)
                 } else {
-                    Err(Exception::LoadAccessFault(addr))
+                    Err(Exception::LoadAccessFault(offset))
                 }
             }
-            _ => Ok(self.uart[(addr - RHR) as usize] as u64),
+            _ => Ok(self.uart[offset as usize] as u64),
         }
     }
 
-    pub fn store(&mut self, addr: u64, value: u64) -> Result<(), Exception> {
-        match addr {
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 8 {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        match offset {
             THR => {
                 print!("{}", value as u8 as char);
                 io::stdout()
@@ -73,9 +71,28 @@ impl UART {
                     .expect("Failed to flush stdout after writing to UART");
             }
             _ => {
-                self.uart[(addr - UART_BASE) as usize] = (value & 0xff) as u8;
+                self.uart[offset as usize] = (value & 0xff) as u8;
             }
         }
         Ok(())
     }
+
+    fn pma(&self) -> Pma {
+        Pma::io(WIDTH_8)
+    }
+
+    fn reset(&mut self) {
+        self.uart.iter_mut().for_each(|reg| *reg = 0);
+        self.uart[LSR as usize] |= LSR_THR_EMPTY;
+        self.irq.lower();
+    }
+
+    fn check_interrupt(&mut self) {
+        if self.in_fd.poll() {
+            self.uart[LSR as usize] |= LSR_DATA_READY;
+            // The receive interrupt is level-triggered: the line stays
+            // asserted for as long as received data is waiting in RHR.
+            self.irq.raise();
+        }
+    }
 }
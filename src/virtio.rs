@@ -0,0 +1,45 @@
+use crate::device::Device;
+use crate::exception::*;
+
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+// Registers of the virtio-mmio transport.
+pub const MAGIC_VALUE: u64 = 0x000;
+pub const VERSION: u64 = 0x004;
+pub const DEVICE_ID: u64 = 0x008;
+
+/// "virt" in little-endian.
+pub const VIRTIO_MAGIC: u64 = 0x7472_6976;
+
+/// A virtio-mmio slot with no device behind it.
+///
+/// Drivers probing the slot find a valid transport reporting device ID 0
+/// and skip it.
+pub struct VirtioMmioSlot;
+
+impl VirtioMmioSlot {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Device for VirtioMmioSlot {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 {
+            return Err(Exception::LoadAccessFault(offset));
+        }
+        Ok(match offset {
+            MAGIC_VALUE => VIRTIO_MAGIC,
+            VERSION => 2,
+            DEVICE_ID => 0,
+            _ => 0,
+        })
+    }
+
+    fn write(&mut self, offset: u64, size: u64, _value: u64) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAMOAccessFault(offset));
+        }
+        Ok(())
+    }
+}